@@ -1,41 +1,18 @@
-use hyper::client::Client;
-use hyper::header::SetCookie;
-use urls::EVUrl;
-use types::{EVError, Folder};
-use std::io::Read;
-use url::form_urlencoded::serialize;
-use cookie::CookieJar;
-use headers::evimproved_headers;
+use session::Session;
+use transport::Transport;
+use types::EVError;
 
-#[cfg(not(test))]
-pub fn login(username: &str, password: &str) -> Result<Folder, EVError> {
-    let data = serialize(&[
-                   ("username", username),
-                   ("password", password),
-                   ("ajax", "true")
-               ]);
-    let headers = evimproved_headers(None);
-    let client = Client::new();
-    let ret = match client.post(EVUrl::Login)
-                .headers(headers)
-                .body(&data)
-                .send() {
-        Ok(mut res) => {
-            let mut ok = String::new();
-            try!(res.read_to_string(&mut ok));
-            match &*ok {
-                "TRUE" => {
-                    let mut jar = CookieJar::new(b"cookiejar");
-                    let session_cookie = res.headers.get::<SetCookie>().unwrap(); // TODO: Result
-                    session_cookie.apply_to_cookie_jar(&mut jar);
-                    Folder::fetch_root(jar)
-                },
-                _ => {
-                    Err(EVError::Authentication("Invalid username or password".into()))
-                }
-            }
-        },
-        Err(e) => Err(EVError::from(e))
-    };
-    ret
+/// Logs in to Elisa Viihde
+/// The returned `Session` logs in again with the same credentials when it expires.
+pub fn login(username: &str, password: &str) -> Result<Session, EVError> {
+    let session = Session::new();
+    try!(session.login(username, password));
+    Ok(session)
+}
+
+/// Logs in to Elisa Viihde through `transport`
+pub fn login_with<T: Transport + 'static>(transport: T, username: &str, password: &str) -> Result<Session, EVError> {
+    let session = Session::with_transport(transport);
+    try!(session.login(username, password));
+    Ok(session)
 }
@@ -0,0 +1,210 @@
+/* vim: set et: */
+
+use cookie::Cookie as CookiePair;
+use hyper::status::StatusCode;
+use rustc_serialize::{json, Decodable, Decoder};
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use transport::{Request, Response, Transport};
+use types::EVError;
+use url::form_urlencoded;
+
+const SCRUBBED: &'static str = "scrubbed";
+
+/// A recorded request and the response to it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Interaction {
+    pub method: String,
+    /// Path and query of the `EVUrl` relative to the base url
+    pub path: String,
+    /// Body of the request with the username and password scrubbed
+    pub body: Option<String>,
+    pub status: u16,
+    /// Cookies set by the response with their values scrubbed
+    pub set_cookies: Vec<String>,
+    pub response: String,
+}
+
+impl Interaction {
+    fn new(request: &Request, response: &Response) -> Interaction {
+        Interaction {
+            method: request.method.to_string(),
+            path: request.url.path(),
+            body: request.body.as_ref().map(|b| scrub_body(b)),
+            status: response.status.to_u16(),
+            set_cookies: response.set_cookies.iter()
+                .map(|c| {
+                    let mut c = c.clone();
+                    c.value = SCRUBBED.into();
+                    c.to_string()
+                })
+                .collect(),
+            response: response.body.clone(),
+        }
+    }
+    fn matches(&self, request: &Request) -> bool {
+        self.method == request.method.to_string() &&
+            self.path == request.url.path() &&
+            self.body == request.body.as_ref().map(|b| scrub_body(b))
+    }
+    fn to_response(&self) -> Response {
+        Response {
+            status: StatusCode::from_u16(self.status),
+            set_cookies: self.set_cookies.iter().filter_map(|c| CookiePair::parse(c).ok()).collect(),
+            body: self.response.clone(),
+        }
+    }
+}
+
+/// Replaces the username and password of a login form
+fn scrub_body(body: &str) -> String {
+    let form = form_urlencoded::parse(body.as_bytes());
+    form_urlencoded::serialize(form.iter().map(|&(ref k, ref v)| {
+        if k == "username" || k == "password" { (&k[..], SCRUBBED) } else { (&k[..], &v[..]) }
+    }))
+}
+
+impl ToJson for Interaction {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("method".to_string(), self.method.to_json());
+        obj.insert("path".to_string(), self.path.to_json());
+        obj.insert("body".to_string(), self.body.to_json());
+        obj.insert("status".to_string(), self.status.to_json());
+        obj.insert("set_cookies".to_string(), self.set_cookies.to_json());
+        obj.insert("response".to_string(), self.response.to_json());
+        Json::Object(obj)
+    }
+}
+
+impl Decodable for Interaction {
+    fn decode<D : Decoder>(d: &mut D) -> Result<Interaction, D::Error> {
+        d.read_struct("", 0, |d| {
+            Ok(Interaction {
+                method: json_field!("method", d),
+                path: json_field!("path", d),
+                body: json_field!("body", d),
+                status: json_field!("status", d),
+                set_cookies: json_field!("set_cookies", d),
+                response: json_field!("response", d),
+            })
+        })
+    }
+}
+
+enum Mode {
+    Record(Box<dyn Transport>, PathBuf),
+    Replay,
+}
+
+/// Transport that records requests and responses to a cassette file or replays them from it
+///
+/// Usernames and passwords in request bodies and the values of cookies are scrubbed before they are
+/// written, so the cassettes can be committed as test fixtures. When replaying, each
+/// request is answered with the first recorded interaction with the same method, url
+/// and body that has not been replayed yet, or with the last one if all have.
+pub struct CassetteTransport {
+    mode: Mode,
+    /// Interactions and whether they have been replayed
+    interactions: Mutex<(Vec<Interaction>, Vec<bool>)>,
+}
+
+impl CassetteTransport {
+    /// Sends the requests through `transport` and records them to `path`
+    /// The cassette is rewritten after every request.
+    pub fn record<T: Transport + 'static, P: AsRef<Path>>(transport: T, path: P) -> CassetteTransport {
+        CassetteTransport {
+            mode: Mode::Record(Box::new(transport), path.as_ref().to_path_buf()),
+            interactions: Mutex::new((Vec::new(), Vec::new())),
+        }
+    }
+    /// Replays the interactions recorded to `path`
+    pub fn replay<P: AsRef<Path>>(path: P) -> Result<CassetteTransport, EVError> {
+        let mut s = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut s));
+        let interactions: Vec<Interaction> = try!(json::decode(&s));
+        let replayed = vec![false; interactions.len()];
+        Ok(CassetteTransport {
+            mode: Mode::Replay,
+            interactions: Mutex::new((interactions, replayed)),
+        })
+    }
+    /// Returns the interactions recorded or loaded so far
+    pub fn interactions(&self) -> Vec<Interaction> {
+        self.interactions.lock().unwrap().0.clone()
+    }
+}
+
+impl Transport for CassetteTransport {
+    fn send(&self, request: &Request) -> Result<Response, EVError> {
+        match self.mode {
+            Mode::Record(ref transport, ref path) => {
+                let response = try!(transport.send(request));
+                let mut interactions = self.interactions.lock().unwrap();
+                interactions.0.push(Interaction::new(request, &response));
+                let mut file = try!(File::create(path));
+                try!(file.write_all(interactions.0.to_json().pretty().to_string().as_bytes()));
+                Ok(response)
+            },
+            Mode::Replay => {
+                let mut interactions = self.interactions.lock().unwrap();
+                let (ref recorded, ref mut replayed) = *interactions;
+                let matching: Vec<usize> = recorded.iter()
+                    .enumerate()
+                    .filter(|&(_, i)| i.matches(request))
+                    .map(|(idx, _)| idx)
+                    .collect();
+                let idx = match matching.iter().find(|&&idx| !replayed[idx]).or(matching.last()) {
+                    Some(&idx) => idx,
+                    None => return Err(EVError::NotRecorded(format!("{} {}", request.method, request.url.path())))
+                };
+                replayed[idx] = true;
+                Ok(recorded[idx].to_response())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CassetteTransport;
+    use session::Session;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use transport::FixtureTransport;
+    use types::{EVError, FolderId};
+    use urls::EVUrl;
+
+    #[test]
+    fn replays_recorded_session() {
+        let path = env::temp_dir().join(format!("evimproved-cassette-{}.json", ::std::process::id()));
+        let fixtures = FixtureTransport::from_dir("testdata").unwrap();
+        let session = Session::with_transport(CassetteTransport::record(fixtures, &path));
+        session.login("matti.meikalainen", "secret password").unwrap();
+        let recorded: Vec<String> = session.root().unwrap().into_iter().map(|r| r.unwrap().name).collect();
+
+        let mut cassette = String::new();
+        File::open(&path).unwrap().read_to_string(&mut cassette).unwrap();
+        assert!(!cassette.contains("secret"));
+        assert!(!cassette.contains("meikalainen"));
+        assert!(!cassette.contains("fixture"));
+
+        let session = Session::with_transport(CassetteTransport::replay(&path).unwrap());
+        session.login("matti.meikalainen", "secret password").unwrap();
+        let replayed: Vec<String> = session.root().unwrap().into_iter().map(|r| r.unwrap().name).collect();
+        assert!(recorded == replayed);
+        match session.get(EVUrl::Folder(FolderId::FolderId(1))) {
+            Err(EVError::Fetch(_, ref e)) => match **e {
+                EVError::NotRecorded(ref request) => assert!(request.starts_with("GET ready.sl?folderid=1&")),
+                _ => assert!(false, "Requests without a recording should not be answered")
+            },
+            _ => assert!(false, "Requests without a recording should not be answered")
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+/* vim: set et: */
+
+use pool::DEFAULT_MAX_IN_FLIGHT;
+use traits::Fetch;
+use types::{EVError, Folder, RecordingInfo};
+use walk::{ErrorPolicy, WalkOptions};
+
+use std::collections::HashMap;
+use std::default::Default;
+
+/// Decides which recording of a duplicate group is kept
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeepPolicy {
+    /// Keep the recording with the newest `timestamp`
+    Newest,
+    /// Keep the recording with the highest `viewcount`
+    MostViewed,
+    /// Keep the recording with the longest `length`
+    Longest,
+}
+
+/// Options for finding duplicate recordings
+#[derive(Clone, Debug)]
+pub struct DedupeOptions {
+    /// Also require the descriptions of the recordings to match.
+    /// Fetches the `Recording` of every recording that would otherwise be a duplicate.
+    pub compare_description: bool,
+    /// Policy used to pick the recording that is kept
+    pub keep: KeepPolicy,
+}
+impl Default for DedupeOptions {
+    fn default() -> DedupeOptions {
+        DedupeOptions {
+            compare_description: false,
+            keep: KeepPolicy::Newest,
+        }
+    }
+}
+
+/// Recordings that are the same programme
+#[derive(Clone, Debug)]
+pub struct DuplicateGroup {
+    /// The recording picked by the `KeepPolicy`
+    pub keeper: RecordingInfo,
+    /// The other recordings of the same programme
+    pub duplicates: Vec<RecordingInfo>,
+}
+
+/// Result of a duplicate search
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub groups: Vec<DuplicateGroup>,
+}
+impl Report {
+    /// Number of recordings that are duplicates of a keeper
+    pub fn duplicate_count(&self) -> usize {
+        self.groups.iter().map(|g| g.duplicates.len()).sum()
+    }
+    /// Iterates over all recordings that are not keepers
+    pub fn duplicates<'a>(&'a self) -> Box<dyn Iterator<Item=&'a RecordingInfo> + 'a> {
+        Box::new(self.groups.iter().flat_map(|g| g.duplicates.iter()))
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    name: String,
+    channel: String,
+    length: i32,
+    description: Option<String>,
+}
+impl Key {
+    fn new(rinfo: &RecordingInfo) -> Key {
+        Key {
+            name: rinfo.name.trim().to_lowercase(),
+            channel: rinfo.channel.clone(),
+            length: rinfo.length,
+            description: None,
+        }
+    }
+}
+
+/// Walks the whole tree under `root` and groups the recordings that are the same programme
+/// The tree is fetched with at most `DEFAULT_MAX_IN_FLIGHT` concurrent requests.
+pub fn find_duplicates(root: &Folder, options: &DedupeOptions) -> Result<Report, EVError> {
+    let walk = root.walk(WalkOptions {
+        on_error: ErrorPolicy::Abort,
+        max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        ..Default::default()
+    });
+    let recordings = try!(walk.recordings().collect());
+    group_duplicates(recordings, options)
+}
+
+/// Groups `recordings` that are the same programme
+/// Recordings are the same programme when their name, channel and length match.
+pub fn group_duplicates(recordings: Vec<RecordingInfo>, options: &DedupeOptions) -> Result<Report, EVError> {
+    let mut candidates = group_by(recordings.into_iter().map(|r| (Key::new(&r), r)));
+    if options.compare_description {
+        let mut described = Vec::new();
+        for (key, group) in candidates {
+            if group.len() < 2 {
+                continue;
+            }
+            for rinfo in group {
+                let mut key = key.clone();
+                let recording = try!(rinfo.fetch_into());
+                key.description = Some(recording.description);
+                described.push((key, recording.info));
+            }
+        }
+        candidates = group_by(described.into_iter());
+    }
+    let mut groups: Vec<DuplicateGroup> = candidates.into_values()
+        .filter(|group| group.len() > 1)
+        .map(|group| pick_keeper(group, options.keep))
+        .collect();
+    groups.sort_by(|a, b| a.keeper.name.cmp(&b.keeper.name).then(a.keeper.id.cmp(&b.keeper.id)));
+    Ok(Report { groups: groups })
+}
+
+fn group_by<I>(recordings: I) -> HashMap<Key, Vec<RecordingInfo>>
+    where I: Iterator<Item=(Key, RecordingInfo)>
+{
+    let mut groups = HashMap::new();
+    for (key, rinfo) in recordings {
+        groups.entry(key).or_insert_with(Vec::new).push(rinfo);
+    }
+    groups
+}
+
+fn pick_keeper(mut group: Vec<RecordingInfo>, policy: KeepPolicy) -> DuplicateGroup {
+    let mut keeper = 0;
+    for (idx, rinfo) in group.iter().enumerate() {
+        let better = match policy {
+            KeepPolicy::Newest => rinfo.timestamp > group[keeper].timestamp,
+            KeepPolicy::MostViewed => rinfo.viewcount > group[keeper].viewcount,
+            KeepPolicy::Longest => rinfo.length > group[keeper].length,
+        };
+        if better {
+            keeper = idx;
+        }
+    }
+    let keeper = group.remove(keeper);
+    DuplicateGroup { keeper: keeper, duplicates: group }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{group_duplicates, DedupeOptions, KeepPolicy};
+    use session::Session;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+    use transport::FixtureTransport;
+    use types::{ProgramId, RecordingInfo};
+    use urls::EVUrl;
+
+    fn recording(id: i32, name: &str, timestamp: &str, viewcount: i32, length: i32) -> RecordingInfo {
+        let mut rinfo = RecordingInfo::default();
+        rinfo.id = id;
+        rinfo.program_id = 1000000 + id;
+        rinfo.name = name.into();
+        rinfo.channel = "MTV3".into();
+        rinfo.timestamp = timestamp.into();
+        rinfo.viewcount = viewcount;
+        rinfo.length = length;
+        rinfo
+    }
+
+    fn recordings() -> Vec<RecordingInfo> {
+        vec![
+            recording(1, "Uutiset", "2014-03-17T00:05:00+0200", 0, 25),
+            recording(2, "Uutiset", "2014-03-18T00:05:00+0200", 2, 25),
+            recording(3, "uutiset ", "2014-03-16T00:05:00+0200", 5, 25),
+            recording(4, "Uutiset", "2014-03-19T00:05:00+0200", 0, 30),
+            recording(5, "Elokuva", "2014-03-19T00:05:00+0200", 0, 90),
+        ]
+    }
+
+    #[test]
+    fn groups_same_programmes() {
+        let report = group_duplicates(recordings(), &DedupeOptions::default()).unwrap();
+        assert!(report.groups.len() == 1);
+        assert!(report.duplicate_count() == 2);
+        assert!(report.groups[0].keeper.id == 2);
+        let mut ids: Vec<i32> = report.duplicates().map(|r| r.id).collect();
+        ids.sort();
+        assert!(ids == vec![1, 3]);
+    }
+
+    #[test]
+    fn keeper_follows_policy() {
+        let options = DedupeOptions { keep: KeepPolicy::MostViewed, ..Default::default() };
+        let report = group_duplicates(recordings(), &options).unwrap();
+        assert!(report.groups[0].keeper.id == 3);
+    }
+
+    #[test]
+    fn descriptions_are_compared() {
+        let options = DedupeOptions { compare_description: true, ..Default::default() };
+        let transport = FixtureTransport::from_dir("testdata").unwrap();
+        // Recordings 3 and 4 are another episode with the same name, channel and length
+        let mut details = String::new();
+        BufReader::new(File::open("testdata/recording_1000003.json").unwrap()).read_line(&mut details).unwrap();
+        let details = details.replace("\"description\": \"\"", "\"description\": \"Toinen%20jakso\"");
+        transport.insert(EVUrl::Program(ProgramId::ProgramId(1000003)), &details);
+        transport.insert(EVUrl::Program(ProgramId::ProgramId(1000004)), &details);
+        let session = Session::with_transport(transport);
+        let mut recs: Vec<RecordingInfo> = (1..5).map(|id| recording(id, "Uutiset", "", 0, 25)).collect();
+        for rinfo in recs.iter_mut() {
+            rinfo.bind(&session);
+        }
+        let report = group_duplicates(recs, &options).unwrap();
+        assert!(report.groups.len() == 2);
+        let mut groups: Vec<Vec<i32>> = report.groups.iter()
+            .map(|g| {
+                let mut ids: Vec<i32> = g.duplicates.iter().chain(Some(&g.keeper)).map(|r| r.id).collect();
+                ids.sort();
+                ids
+            })
+            .collect();
+        groups.sort();
+        assert!(groups == vec![vec![1, 2], vec![3, 4]]);
+    }
+}
@@ -0,0 +1,368 @@
+/* vim: set et: */
+
+use hyper::client::Client;
+use hyper::header::{ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, Range};
+use hyper::status::StatusCode;
+use types::{EVError, Recording};
+use url::Url;
+
+use std::default::Default;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// Size of the buffer the media is copied through
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Response to a request for the media of a recording
+pub struct Body {
+    pub status: StatusCode,
+    /// Offset of the first byte of `reader` in the whole media
+    pub offset: u64,
+    /// Size of the whole media, if the server reported it
+    pub total: Option<u64>,
+    pub reader: Box<dyn Read + Send>,
+}
+
+/// Fetches the media of recordings for a `Downloader`
+pub trait Source: Send + Sync {
+    /// Requests the media at `url` starting from the byte at `offset`
+    /// A source may ignore the offset and return the media from its start.
+    fn open(&self, url: &Url, offset: u64) -> Result<Body, EVError>;
+}
+
+/// Source that fetches the media over HTTP, resuming with range requests
+pub struct HyperSource {
+    client: Client,
+}
+
+impl Default for HyperSource {
+    fn default() -> HyperSource {
+        HyperSource::new()
+    }
+}
+
+impl HyperSource {
+    pub fn new() -> HyperSource {
+        HyperSource { client: Client::new() }
+    }
+}
+
+impl Source for HyperSource {
+    fn open(&self, url: &Url, offset: u64) -> Result<Body, EVError> {
+        let url = url.to_string();
+        let mut req = self.client.get(&url[..]);
+        if offset > 0 {
+            req = req.header(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+        }
+        let res = try!(req.send());
+        let (start, total) = match res.headers.get::<ContentRange>() {
+            Some(&ContentRange(ContentRangeSpec::Bytes { range, instance_length })) => {
+                (range.map_or(0, |(first, _)| first), instance_length)
+            },
+            _ => (0, res.headers.get::<ContentLength>().map(|&ContentLength(len)| len))
+        };
+        Ok(Body {
+            status: res.status,
+            offset: start,
+            total: total,
+            reader: Box::new(res),
+        })
+    }
+}
+
+/// Progress of a download
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// Number of bytes downloaded, including the ones of earlier attempts
+    pub downloaded: u64,
+    /// Size of the whole media, if the server reported it
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    /// Returns the downloaded share of the media between 0 and 1
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some(self.downloaded as f64 / total as f64),
+            None => None
+        }
+    }
+}
+
+/// How failed transfers are retried
+/// Connection and I/O errors, server errors and transfers that end early are retried,
+/// continuing from the last byte that was written.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    /// Time to wait before each retry
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Options of a `Downloader`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DownloadOptions {
+    pub retry: RetryPolicy,
+    /// Continue downloading to an existing file instead of starting over
+    pub resume: bool,
+    /// Fail unless the number of bytes downloaded matches the size the server reported
+    pub verify_size: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            retry: Default::default(),
+            resume: true,
+            verify_size: true,
+        }
+    }
+}
+
+/// Downloads the media of recordings to local files
+pub struct Downloader {
+    source: Box<dyn Source>,
+    options: DownloadOptions,
+}
+
+impl Downloader {
+    /// Creates a downloader that fetches the media over HTTP
+    pub fn new(options: DownloadOptions) -> Downloader {
+        Downloader::with_source(HyperSource::new(), options)
+    }
+    /// Creates a downloader that fetches the media from `source`
+    pub fn with_source<S: Source + 'static>(source: S, options: DownloadOptions) -> Downloader {
+        Downloader {
+            source: Box::new(source),
+            options: options,
+        }
+    }
+    /// Returns the options of this downloader
+    pub fn options(&self) -> &DownloadOptions {
+        &self.options
+    }
+    /// Downloads the media of `recording` to `path`
+    /// See `Downloader::download_file`.
+    pub fn download_recording<P, F>(&self, recording: &Recording, path: P, progress: F) -> Result<u64, EVError>
+        where P: AsRef<Path>, F: FnMut(&Progress) {
+        self.download_file(&recording.url, path, progress)
+    }
+    /// Downloads the media at `url` to `path`
+    /// An existing file is continued from its end if the options allow resuming and
+    /// replaced otherwise. Returns the size of the file.
+    pub fn download_file<P, F>(&self, url: &Url, path: P, progress: F) -> Result<u64, EVError>
+        where P: AsRef<Path>, F: FnMut(&Progress) {
+        let mut options = OpenOptions::new();
+        if self.options.resume {
+            options.append(true).create(true);
+        }
+        else {
+            options.write(true).create(true).truncate(true);
+        }
+        let mut file = try!(options.open(path));
+        let offset = try!(file.metadata()).len();
+        let size = try!(self.download_to(url, &mut file, offset, progress));
+        try!(file.sync_all());
+        Ok(size)
+    }
+    /// Writes the media at `url` to `out`, starting from the byte at `offset`
+    /// `progress` is called after every chunk that was written. Returns the number of
+    /// bytes of the media there are after the download, `offset` included.
+    pub fn download_to<W, F>(&self, url: &Url, out: &mut W, offset: u64, mut progress: F) -> Result<u64, EVError>
+        where W: Write, F: FnMut(&Progress) {
+        let mut written = offset;
+        let mut retries = 0;
+        loop {
+            match self.transfer(url, out, &mut written, &mut progress) {
+                Ok(()) => return Ok(written),
+                Err(e) => {
+                    if retries >= self.options.retry.max_retries || !is_retryable(&e) {
+                        return Err(e);
+                    }
+                    retries += 1;
+                    thread::sleep(self.options.retry.delay);
+                }
+            }
+        }
+    }
+    fn transfer<W, F>(&self, url: &Url, out: &mut W, written: &mut u64, progress: &mut F) -> Result<(), EVError>
+        where W: Write, F: FnMut(&Progress) {
+        let mut body = try!(self.source.open(url, *written));
+        match body.status {
+            StatusCode::Ok | StatusCode::PartialContent => {},
+            // The whole media was downloaded before
+            StatusCode::RangeNotSatisfiable if body.total == Some(*written) => return Ok(()),
+            status => return Err(EVError::Status(status))
+        }
+        if body.offset > *written {
+            return Err(EVError::UnexpectedShape(format!("The media starts at byte {} instead of {}", body.offset, *written)));
+        }
+        // Servers that do not support ranges send the media from the start
+        let mut skip = *written - body.offset;
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let n = match body.reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(EVError::from(e))
+            };
+            let skipped = if skip < n as u64 { skip as usize } else { n };
+            skip -= skipped as u64;
+            try!(out.write_all(&buf[skipped..n]));
+            *written += (n - skipped) as u64;
+            progress(&Progress { downloaded: *written, total: body.total });
+        }
+        try!(out.flush());
+        match body.total {
+            Some(total) if self.options.verify_size && total != *written => Err(EVError::SizeMismatch(total, *written)),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Returns true if a transfer that failed with `e` can be continued
+fn is_retryable(e: &EVError) -> bool {
+    match *e {
+        EVError::IO(_) | EVError::Http(_) => true,
+        EVError::Status(ref status) => status.is_server_error(),
+        EVError::SizeMismatch(expected, actual) => actual < expected,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Body, DownloadOptions, Downloader, Progress, RetryPolicy, Source};
+    use hyper::status::StatusCode;
+    use std::default::Default;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{self, Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use types::EVError;
+    use url::Url;
+
+    /// Serves `data`, ending the transfers early as many times as there are cut points
+    #[derive(Clone)]
+    struct MemorySource {
+        data: Vec<u8>,
+        supports_ranges: bool,
+        /// Number of bytes sent before the next transfers fail
+        cuts: Arc<Mutex<Vec<usize>>>,
+        offsets: Arc<Mutex<Vec<u64>>>,
+    }
+
+    struct CutReader {
+        data: io::Cursor<Vec<u8>>,
+        left: Option<usize>,
+    }
+
+    impl Read for CutReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.left {
+                Some(0) => Err(io::Error::new(io::ErrorKind::ConnectionReset, "cut")),
+                Some(left) => {
+                    let len = if left < buf.len() { left } else { buf.len() };
+                    let n = try!(self.data.read(&mut buf[..len]));
+                    self.left = Some(left - n);
+                    Ok(n)
+                },
+                None => self.data.read(buf)
+            }
+        }
+    }
+
+    impl MemorySource {
+        fn new(len: usize, supports_ranges: bool, cuts: Vec<usize>) -> MemorySource {
+            MemorySource {
+                data: (0..len).map(|i| (i % 251) as u8).collect(),
+                supports_ranges: supports_ranges,
+                cuts: Arc::new(Mutex::new(cuts)),
+                offsets: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    impl Source for MemorySource {
+        fn open(&self, _: &Url, offset: u64) -> Result<Body, EVError> {
+            self.offsets.lock().unwrap().push(offset);
+            let start = if self.supports_ranges { offset as usize } else { 0 };
+            let mut cuts = self.cuts.lock().unwrap();
+            let left = if cuts.is_empty() { None } else { Some(cuts.remove(0)) };
+            Ok(Body {
+                status: if start > 0 { StatusCode::PartialContent } else { StatusCode::Ok },
+                offset: start as u64,
+                total: Some(self.data.len() as u64),
+                reader: Box::new(CutReader { data: io::Cursor::new(self.data[start..].to_vec()), left: left }),
+            })
+        }
+    }
+
+    fn options(max_retries: usize) -> DownloadOptions {
+        DownloadOptions {
+            retry: RetryPolicy { max_retries: max_retries, delay: Duration::from_millis(0) },
+            ..Default::default()
+        }
+    }
+
+    fn url() -> Url {
+        Url::parse("http://google.fi/").unwrap()
+    }
+
+    #[test]
+    fn resumes_interrupted_transfers() {
+        for &supports_ranges in [true, false].iter() {
+            let source = MemorySource::new(200000, supports_ranges, vec![100000, 50000]);
+            let downloader = Downloader::with_source(source.clone(), options(3));
+            let mut out = Vec::new();
+            let mut last = None;
+            let size = downloader.download_to(&url(), &mut out, 0, |p: &Progress| last = Some(*p)).unwrap();
+            assert!(size == 200000);
+            assert!(out == source.data);
+            // Without ranges the bytes sent before the cut are the ones already written
+            let offsets = if supports_ranges { vec![0, 100000, 150000] } else { vec![0, 100000, 100000] };
+            assert!(*source.offsets.lock().unwrap() == offsets);
+            assert!(last.and_then(|p| p.fraction()) == Some(1.0));
+        }
+    }
+
+    #[test]
+    fn gives_up_after_the_retries() {
+        let source = MemorySource::new(1000, true, vec![10, 10, 10]);
+        let downloader = Downloader::with_source(source.clone(), options(1));
+        match downloader.download_to(&url(), &mut Vec::new(), 0, |_: &Progress| {}) {
+            Err(EVError::IO(_)) => {},
+            _ => assert!(false, "The transfer should fail after one retry")
+        }
+        assert!(source.offsets.lock().unwrap().len() == 2);
+    }
+
+    #[test]
+    fn continues_partial_files() {
+        let source = MemorySource::new(5000, true, Vec::new());
+        let path = env::temp_dir().join(format!("evimproved-download-{}", ::std::process::id()));
+        File::create(&path).unwrap().write_all(&source.data[..1234]).unwrap();
+        let downloader = Downloader::with_source(source.clone(), options(0));
+        assert!(downloader.download_file(&url(), &path, |_: &Progress| {}).unwrap() == 5000);
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(data == source.data);
+        assert!(*source.offsets.lock().unwrap() == vec![1234]);
+    }
+}
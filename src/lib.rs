@@ -6,9 +6,27 @@ extern crate cookie;
 extern crate hyper;
 extern crate url;
 extern crate rustc_serialize;
+extern crate time;
 
-mod urls;
+pub mod urls;
+#[macro_use]
+pub mod types;
 pub mod traits;
 pub mod authentication;
-pub mod types;
+pub mod session;
+pub mod transport;
+pub mod cassette;
 pub mod headers;
+pub mod dedupe;
+pub mod plan;
+pub mod pool;
+pub mod walk;
+pub mod timestamp;
+pub mod usage;
+pub mod retention;
+pub mod download;
+pub mod queue;
+pub mod naming;
+pub mod nfo;
+#[cfg(feature = "mock-server")]
+pub mod mock;
@@ -0,0 +1,435 @@
+/* vim: set et: */
+
+//! Embeddable mock of the Elisa Viihde API for integration tests
+//!
+//! Enabled with the `mock-server` feature.
+
+use cookie::Cookie as CookiePair;
+use hyper::header::{Cookie, SetCookie};
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use rustc_serialize::json::{DecoderError, Json, Object};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::usize;
+use types::EVError;
+use url::form_urlencoded;
+
+const SESSION_COOKIE: &'static str = "JSESSIONID";
+
+struct MockFolder {
+    parent: i32,
+    info: Object,
+}
+
+struct MockRecording {
+    folder: i32,
+    info: Object,
+}
+
+/// In-memory library of folders and recordings served by `MockServer`
+pub struct Library {
+    folders: BTreeMap<i32, MockFolder>,
+    recordings: BTreeMap<i32, MockRecording>,
+    programs: BTreeMap<i32, Json>,
+    credentials: Option<(String, String)>,
+    sessions: Vec<String>,
+    next_session: usize,
+    page_size: usize,
+}
+
+impl Default for Library {
+    fn default() -> Library {
+        Library::new()
+    }
+}
+
+impl Library {
+    /// Creates an empty library that accepts any credentials
+    pub fn new() -> Library {
+        Library {
+            folders: BTreeMap::new(),
+            recordings: BTreeMap::new(),
+            programs: BTreeMap::new(),
+            credentials: None,
+            sessions: Vec::new(),
+            next_session: 0,
+            page_size: usize::MAX,
+        }
+    }
+    /// Creates a library from fixtures in the format of the `testdata` directory
+    /// See `transport::FixtureTransport::from_dir` for the file names.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Library, EVError> {
+        let mut library = Library::new();
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            let stem = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(stem) => stem.to_owned(),
+                None => continue
+            };
+            let id = if stem == "root_folder" {
+                Some(0)
+            }
+            else if let Some(id) = stem.strip_prefix("folder_") {
+                id.parse().ok()
+            }
+            else if let Some(id) = stem.strip_prefix("recording_") {
+                if let Ok(id) = id.parse() {
+                    library.programs.insert(id, try!(read_fixture(&path)));
+                }
+                None
+            }
+            else {
+                None
+            };
+            if let Some(id) = id {
+                try!(library.add_listing(id, &try!(read_fixture(&path))));
+            }
+        }
+        Ok(library)
+    }
+    /// Accepts only `username` and `password` when logging in
+    pub fn set_credentials(&mut self, username: &str, password: &str) {
+        self.credentials = Some((username.into(), password.into()));
+    }
+    /// Lists at most `page_size` recordings per page of a folder listing
+    /// By default all recordings are listed on the first page.
+    pub fn set_page_size(&mut self, page_size: usize) {
+        self.page_size = page_size;
+    }
+    /// Invalidates all session cookies that have been handed out
+    pub fn expire_sessions(&mut self) {
+        self.sessions.clear();
+    }
+    /// Returns the id of the folder the recording with `id` is in, 0 being the root folder
+    pub fn folder_of(&self, id: i32) -> Option<i32> {
+        self.recordings.get(&id).map(|r| r.folder)
+    }
+    /// Moves the recording with `id` to the folder `destination`
+    pub fn move_recording(&mut self, id: i32, destination: i32) -> bool {
+        if destination != 0 && !self.folders.contains_key(&destination) {
+            return false;
+        }
+        match self.recordings.get_mut(&id) {
+            Some(rec) => {
+                rec.folder = destination;
+                let folder_id = if destination == 0 { String::new() } else { destination.to_string() };
+                rec.info.insert("folder_id".into(), Json::String(folder_id));
+                true
+            },
+            None => false
+        }
+    }
+    /// Returns the id of the recording whose program has the program view id `id`
+    fn recording_with_program_view(&self, id: i32) -> Option<i32> {
+        let program = self.programs.iter()
+            .find(|&(_, p)| json_i32(p.find("programviewid")) == Some(id))
+            .map(|(&program, _)| program);
+        program.and_then(|program| {
+            self.recordings.iter()
+                .find(|&(_, r)| json_i32(r.info.get("program_id")) == Some(program))
+                .map(|(&id, _)| id)
+        })
+    }
+    /// Removes the recording with `id`
+    pub fn delete_recording(&mut self, id: i32) -> bool {
+        self.recordings.remove(&id).is_some()
+    }
+    /// Returns true if the library has a recording with `id`
+    pub fn contains_recording(&self, id: i32) -> bool {
+        self.recordings.contains_key(&id)
+    }
+    fn add_listing(&mut self, folder: i32, listing: &Json) -> Result<(), EVError> {
+        let data = try!(listing.find("ready_data")
+            .and_then(Json::as_array)
+            .and_then(|a| a.first())
+            .ok_or(EVError::Decoder(DecoderError::MissingFieldError("ready_data".into()))));
+        for f in data.find("folders").and_then(Json::as_array).into_iter().flat_map(|a| a.iter()) {
+            if let Some(info) = f.as_object() {
+                if let Some(id) = json_i32(f.find("id")) {
+                    self.folders.insert(id, MockFolder { parent: folder, info: info.clone() });
+                }
+            }
+        }
+        for r in data.find("recordings").and_then(Json::as_array).into_iter().flat_map(|a| a.iter()) {
+            if let Some(info) = r.as_object() {
+                if let Some(id) = json_i32(r.find("id")) {
+                    self.recordings.insert(id, MockRecording { folder: folder, info: info.clone() });
+                }
+            }
+        }
+        Ok(())
+    }
+    fn listing(&self, folder: i32, page: usize) -> Option<Json> {
+        if folder != 0 && !self.folders.contains_key(&folder) {
+            return None;
+        }
+        let page_size = self.page_size;
+        let folders = self.folders.iter()
+            .filter(|&(_, f)| f.parent == folder && page == 0)
+            .map(|(id, f)| {
+                let mut info = f.info.clone();
+                let count = self.recordings.values().filter(|r| r.folder == *id).count();
+                info.insert("recordings_count".into(), Json::String(count.to_string()));
+                Json::Object(info)
+            })
+            .collect();
+        let recordings = self.recordings.values()
+            .filter(|r| r.folder == folder)
+            .skip(page.saturating_mul(page_size))
+            .take(page_size)
+            .map(|r| Json::Object(r.info.clone()))
+            .collect();
+        let mut data = BTreeMap::new();
+        data.insert("folders".to_string(), Json::Array(folders));
+        data.insert("recordings".to_string(), Json::Array(recordings));
+        let mut obj = BTreeMap::new();
+        obj.insert("ready_data".to_string(), Json::Array(vec![Json::Object(data)]));
+        Some(Json::Object(obj))
+    }
+    fn login(&mut self, username: &str, password: &str) -> Option<String> {
+        let accepted = match self.credentials {
+            Some((ref u, ref p)) => u == username && p == password,
+            None => true
+        };
+        if accepted {
+            self.next_session += 1;
+            let token = format!("mock{}", self.next_session);
+            self.sessions.push(token.clone());
+            Some(token)
+        }
+        else {
+            None
+        }
+    }
+    fn is_logged_in(&self, cookies: &[CookiePair]) -> bool {
+        cookies.iter().any(|c| c.name == SESSION_COOKIE && self.sessions.contains(&c.value))
+    }
+}
+
+fn read_fixture(path: &Path) -> Result<Json, EVError> {
+    let mut line = String::new();
+    try!(BufReader::new(try!(File::open(path))).read_line(&mut line));
+    Json::from_str(&line).map_err(EVError::from)
+}
+
+fn json_i32(json: Option<&Json>) -> Option<i32> {
+    match json {
+        Some(&Json::String(ref s)) => s.parse().ok(),
+        Some(json) => json.as_i64().map(|n| n as i32),
+        None => None
+    }
+}
+
+struct MockHandler {
+    library: Arc<Mutex<Library>>,
+}
+
+impl MockHandler {
+    fn route(&self, method: &Method, path: &str, query: &str, body: &str, cookies: &[CookiePair])
+        -> (StatusCode, Option<CookiePair>, String)
+    {
+        let params = form_urlencoded::parse(query.as_bytes());
+        let param = |name: &str| params.iter().find(|&&(ref k, _)| k == name).map(|&(_, ref v)| v.clone());
+        let mut library = self.library.lock().unwrap();
+        if path == "login.sl" {
+            if *method != Method::Post {
+                return (StatusCode::MethodNotAllowed, None, String::new());
+            }
+            let form = form_urlencoded::parse(body.as_bytes());
+            let field = |name: &str| form.iter().find(|&&(ref k, _)| k == name).map(|&(_, ref v)| v.clone()).unwrap_or_default();
+            return match library.login(&field("username"), &field("password")) {
+                Some(token) => (StatusCode::Ok, Some(CookiePair::new(SESSION_COOKIE.into(), token)), "TRUE".into()),
+                None => (StatusCode::Ok, None, "FALSE".into())
+            };
+        }
+        if !library.is_logged_in(cookies) {
+            return (StatusCode::Forbidden, None, String::new());
+        }
+        match path {
+            "ready.sl" if param("move").as_ref().map(|m| &m[..]) == Some("true") => {
+                let id = param("programviewid").and_then(|id| id.parse().ok())
+                    .and_then(|id| library.recording_with_program_view(id));
+                let destination = param("destination").and_then(|id| id.parse().ok());
+                match (id, destination) {
+                    (Some(id), Some(destination)) if library.move_recording(id, destination) => {
+                        (StatusCode::Ok, None, "TRUE".into())
+                    },
+                    _ => (StatusCode::Ok, None, "FALSE".into())
+                }
+            },
+            "ready.sl" if param("remove").as_ref().map(|m| &m[..]) == Some("true") => {
+                match param("programviewid").and_then(|id| id.parse().ok()).and_then(|id| library.recording_with_program_view(id)) {
+                    Some(id) if library.delete_recording(id) => (StatusCode::Ok, None, "TRUE".into()),
+                    _ => (StatusCode::Ok, None, "FALSE".into())
+                }
+            },
+            "ready.sl" => {
+                let folder = param("folderid").and_then(|id| id.parse().ok()).unwrap_or(0);
+                let page = param("ppos").and_then(|page| page.parse().ok()).unwrap_or(0);
+                match library.listing(folder, page) {
+                    Some(listing) => (StatusCode::Ok, None, listing.to_string()),
+                    None => (StatusCode::NotFound, None, String::new())
+                }
+            },
+            "program.sl" => {
+                let program = param("programid").and_then(|id| id.parse().ok())
+                    .and_then(|id| library.programs.get(&id));
+                match program {
+                    Some(program) => (StatusCode::Ok, None, program.to_string()),
+                    None => (StatusCode::NotFound, None, String::new())
+                }
+            },
+            _ => (StatusCode::NotFound, None, String::new())
+        }
+    }
+}
+
+impl Handler for MockHandler {
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: Response<'a, Fresh>) {
+        let uri = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new()
+        };
+        let (path, query) = match uri.find('?') {
+            Some(idx) => (uri[..idx].to_owned(), uri[idx + 1..].to_owned()),
+            None => (uri.clone(), String::new())
+        };
+        let endpoint = path.rsplit('/').next().unwrap_or("").to_owned();
+        let cookies = match req.headers.get::<Cookie>() {
+            Some(&Cookie(ref cookies)) => cookies.clone(),
+            None => Vec::new()
+        };
+        let mut body = String::new();
+        let _ = req.read_to_string(&mut body);
+        let (status, set_cookie, body) = self.route(&req.method, &endpoint, &query, &body, &cookies);
+        *res.status_mut() = status;
+        if let Some(cookie) = set_cookie {
+            res.headers_mut().set(SetCookie(vec![cookie]));
+        }
+        let _ = res.send(body.as_bytes());
+    }
+}
+
+/// HTTP server that implements the Elisa Viihde API over a `Library`
+/// The server stops listening when it is dropped.
+pub struct MockServer {
+    listening: Listening,
+    library: Arc<Mutex<Library>>,
+}
+
+impl MockServer {
+    /// Starts a server on a free local port
+    pub fn start(library: Library) -> Result<MockServer, EVError> {
+        let library = Arc::new(Mutex::new(library));
+        let server = try!(Server::http("127.0.0.1:0"));
+        let listening = try!(server.handle(MockHandler { library: library.clone() }));
+        Ok(MockServer { listening: listening, library: library })
+    }
+    /// Returns the base url to use with `Session::with_base_url`
+    pub fn base_url(&self) -> String {
+        format!("http://{}/etvrecorder/", self.listening.socket)
+    }
+    /// Gives access to the library the server is serving
+    pub fn library<'a>(&'a self) -> MutexGuard<'a, Library> {
+        self.library.lock().unwrap()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        let _ = self.listening.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Library, MockServer};
+    use plan::Plan;
+    use session::Session;
+    use traits::Fetch;
+
+    fn start() -> (MockServer, Session) {
+        let mut library = Library::from_dir("testdata").unwrap();
+        library.set_credentials("username", "password");
+        let server = MockServer::start(library).unwrap();
+        let session = Session::with_base_url(&server.base_url());
+        (server, session)
+    }
+
+    #[test]
+    fn rejects_invalid_credentials() {
+        let (_server, session) = start();
+        assert!(session.login("username", "wrong").is_err());
+        assert!(session.root().is_err());
+    }
+
+    #[test]
+    fn serves_the_tree() {
+        let (_server, session) = start();
+        session.login("username", "password").unwrap();
+        let root = session.root().unwrap();
+        assert!(root.folders().count() == 2);
+        assert!(root.recordings().count() == 2);
+        let names: Vec<String> = root.into_iter().map(|r| r.unwrap().name).collect();
+        assert!(names.len() == 4);
+        let rec = session.root().unwrap().recordings().next().unwrap().clone();
+        assert!(rec.fetch().unwrap().name == "Tämä on testi");
+    }
+
+    #[test]
+    fn moves_are_reflected_in_listings() {
+        let (server, session) = start();
+        session.login("username", "password").unwrap();
+        let root = session.root().unwrap();
+        let destination = root.find_by_name("Foldername").unwrap();
+        let rec = root.recordings().next().unwrap();
+        rec.move_to(&destination).unwrap();
+        assert!(server.library().folder_of(rec.id) == Some(1000001));
+        assert!(session.root().unwrap().recordings().count() == 1);
+        assert!(destination.fetch().unwrap().recordings().count() == 2);
+    }
+
+    #[test]
+    fn expired_sessions_log_in_again() {
+        let (server, session) = start();
+        session.login("username", "password").unwrap();
+        server.library().expire_sessions();
+        assert!(session.root().is_ok());
+    }
+
+    #[test]
+    fn follows_pages_of_large_folders() {
+        let (server, session) = start();
+        session.login("username", "password").unwrap();
+        for rec in session.root().unwrap().recordings() {
+            assert!(server.library().move_recording(rec.id, 1000001));
+        }
+        server.library().set_page_size(1);
+        let folder = session.root().unwrap().find_by_name("Foldername").unwrap();
+        assert!(folder.recordings_count == 3);
+        assert!(folder.fetch().unwrap().recordings().count() == 3);
+        assert!(folder.pages().count() == 3);
+        let first: Vec<_> = folder.pages().recordings().take(1).collect();
+        assert!(first.len() == 1 && first[0].is_ok());
+    }
+
+    #[test]
+    fn deletes_recordings() {
+        let (server, session) = start();
+        session.login("username", "password").unwrap();
+        let root = session.root().unwrap();
+        let rec = root.recordings().next().unwrap().clone();
+        let mut plan = Plan::new();
+        plan.delete_recording(&rec, "test");
+        assert!(plan.apply(&root).unwrap().is_complete());
+        assert!(!server.library().contains_recording(rec.id));
+        assert!(session.root().unwrap().recordings().count() == 1);
+    }
+}
@@ -0,0 +1,435 @@
+/* vim: set et: */
+
+use pool::DEFAULT_MAX_IN_FLIGHT;
+use timestamp::Timestamp;
+use types::{EVError, Folder, Recording, RecordingInfo};
+use walk::{Entry, ErrorPolicy, WalkOptions};
+
+use std::collections::HashSet;
+use std::default::Default;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use time;
+
+/// Format of times without a format in the template, without characters that are
+/// not allowed in file names
+const DEFAULT_TIME_FORMAT: &'static str = "%Y-%m-%d %H.%M";
+
+/// Field of a recording that can be used in a `Template`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Field {
+    Name,
+    Channel,
+    Folder,
+    Id,
+    ProgramId,
+    Length,
+    StartTime,
+    Timestamp,
+    EndTime,
+    ShortText,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "name" => Some(Field::Name),
+            "channel" => Some(Field::Channel),
+            "folder" => Some(Field::Folder),
+            "id" => Some(Field::Id),
+            "program_id" => Some(Field::ProgramId),
+            "length" => Some(Field::Length),
+            "start_time" => Some(Field::StartTime),
+            "timestamp" => Some(Field::Timestamp),
+            "end_time" => Some(Field::EndTime),
+            "short_text" => Some(Field::ShortText),
+            _ => None
+        }
+    }
+    fn is_time(&self) -> bool {
+        match *self {
+            Field::StartTime | Field::Timestamp | Field::EndTime => true,
+            _ => false
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Field, Option<String>),
+}
+
+/// Template of the path a recording is saved to
+///
+/// Fields in braces are replaced with the values of the recording, `/` separates
+/// directories and `{{` and `}}` are literal braces:
+///
+/// * `{name}`, `{channel}`, `{id}`, `{program_id}` and `{length}` in minutes
+/// * `{folder}`, the name of the folder of the recording
+/// * `{start_time}`, `{timestamp}` and `{end_time}` in Helsinki time, optionally with
+///   a `strftime` format like `{start_time:%Y-%m-%d}`
+/// * `{short_text}`
+///
+/// `{end_time}` and `{short_text}` are only known from the `Recording` and are empty
+/// when only the `RecordingInfo` is available.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => { chars.next(); text.push('{'); },
+                '}' if chars.peek() == Some(&'}') => { chars.next(); text.push('}'); },
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(format!("Unclosed field in template: {}", s))
+                        }
+                    }
+                    let (name, format) = match spec.find(':') {
+                        Some(idx) => (&spec[..idx], Some(spec[idx + 1..].to_string())),
+                        None => (&spec[..], None)
+                    };
+                    let field = try!(Field::from_name(name.trim()).ok_or(format!("Unknown field: {}", name)));
+                    if let Some(ref format) = format {
+                        if !field.is_time() {
+                            return Err(format!("Field {} can not be formatted", name));
+                        }
+                        try!(time::strftime(format, &time::empty_tm()).map_err(|e| format!("Invalid time format {}: {}", format, e)));
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(text.split_off(0)));
+                    }
+                    parts.push(Part::Field(field, format));
+                },
+                '}' => return Err(format!("Unmatched }} in template: {}", s)),
+                c => text.push(c)
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { source: s.into(), parts: parts })
+    }
+}
+
+impl Template {
+    /// Returns the extension this template ends the file names with, like `ts`
+    /// Returns None if the file name ends with a field, since a dot in a value
+    /// does not start an extension.
+    pub fn extension(&self) -> Option<&str> {
+        match self.parts.last() {
+            Some(&Part::Text(ref text)) => {
+                let name = &text[text.rfind('/').map(|idx| idx + 1).unwrap_or(0)..];
+                name.rfind('.').map(|idx| &name[idx + 1..]).filter(|ext| !ext.is_empty())
+            },
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.source)
+    }
+}
+
+impl Default for Template {
+    /// `{name} ({id}).ts`
+    fn default() -> Template {
+        "{name} ({id}).ts".parse().unwrap()
+    }
+}
+
+/// What to do when two recordings would be saved to the same path
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Collision {
+    /// Append ` (2)`, ` (3)` and so on to the file name
+    Number,
+    /// Append the id of the recording to the file name, then a number if it still collides
+    Id,
+}
+
+/// Options for naming the files of recordings
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamingOptions {
+    pub template: Template,
+    /// Replace Finnish and other non-ASCII letters with ASCII ones, like `ä` with `a`
+    pub ascii: bool,
+    /// Save the recordings in directories named after the folders they are in
+    /// in Elisa Viihde, in addition to the directories of the template
+    pub mirror_folders: bool,
+    pub on_collision: Collision,
+}
+
+impl Default for NamingOptions {
+    fn default() -> NamingOptions {
+        NamingOptions {
+            template: Default::default(),
+            ascii: false,
+            mirror_folders: false,
+            on_collision: Collision::Number,
+        }
+    }
+}
+
+/// Names the files of recordings so that no two recordings get the same path
+/// Paths are compared case-insensitively, since not all file systems tell them apart.
+#[derive(Clone, Debug)]
+pub struct Namer {
+    options: NamingOptions,
+    assigned: HashSet<String>,
+}
+
+impl Namer {
+    pub fn new(options: NamingOptions) -> Namer {
+        Namer { options: options, assigned: HashSet::new() }
+    }
+    /// Returns the options of this namer
+    pub fn options(&self) -> &NamingOptions {
+        &self.options
+    }
+    /// Marks `path` as taken, for example by a file saved earlier
+    pub fn reserve(&mut self, path: &Path) {
+        self.assigned.insert(collision_key(path));
+    }
+    /// Returns a relative path for `rinfo`
+    /// `folders` are the names of the folders from the root to the folder of the recording,
+    /// the root excluded.
+    pub fn path(&mut self, rinfo: &RecordingInfo, folders: &[String]) -> PathBuf {
+        self.assign(rinfo, None, folders)
+    }
+    /// Returns a relative path for `recording`, with all fields of the template known
+    pub fn path_for_recording(&mut self, recording: &Recording, folders: &[String]) -> PathBuf {
+        self.assign(&recording.info, Some(recording), folders)
+    }
+    /// Names every recording of the tree under `root`
+    pub fn paths_for_tree(&mut self, root: &Folder) -> Result<Vec<(RecordingInfo, PathBuf)>, EVError> {
+        let recordings = try!(recordings_with_folders(root));
+        Ok(recordings.into_iter()
+            .map(|(rinfo, folders)| {
+                let path = self.path(&rinfo, &folders);
+                (rinfo, path)
+            })
+            .collect())
+    }
+    fn assign(&mut self, rinfo: &RecordingInfo, recording: Option<&Recording>, folders: &[String]) -> PathBuf {
+        let mut components: Vec<String> = Vec::new();
+        if self.options.mirror_folders {
+            components.extend(folders.iter().map(|f| self.sanitize(f)));
+        }
+        let rendered = self.render(rinfo, recording, folders);
+        components.extend(rendered.split('/').map(|c| c.to_string()));
+        let components: Vec<String> = components.into_iter()
+            .map(|c| c.trim().trim_end_matches('.').to_string())
+            .filter(|c| !c.is_empty())
+            .collect();
+        let (dirs, file) = match components.split_last() {
+            Some((file, dirs)) => (dirs.to_vec(), file.clone()),
+            None => (Vec::new(), rinfo.id.to_string())
+        };
+        let (stem, ext) = match file.rfind('.') {
+            Some(idx) if idx > 0 => (file[..idx].to_string(), file[idx..].to_string()),
+            _ => (file.clone(), String::new())
+        };
+        let build = |stem: &str| -> PathBuf {
+            let mut path: PathBuf = dirs.iter().collect();
+            path.push(format!("{}{}", stem, ext));
+            path
+        };
+        let mut path = build(&stem);
+        if self.assigned.contains(&collision_key(&path)) {
+            let base = match self.options.on_collision {
+                Collision::Number => stem.clone(),
+                Collision::Id => format!("{} [{}]", stem, rinfo.id),
+            };
+            path = build(&base);
+            let mut n = 2;
+            while self.assigned.contains(&collision_key(&path)) {
+                path = build(&format!("{} ({})", base, n));
+                n += 1;
+            }
+        }
+        self.assigned.insert(collision_key(&path));
+        path
+    }
+    fn render(&self, rinfo: &RecordingInfo, recording: Option<&Recording>, folders: &[String]) -> String {
+        let mut out = String::new();
+        for part in self.options.template.parts.iter() {
+            let value = match *part {
+                Part::Text(ref text) => {
+                    out.push_str(text);
+                    continue;
+                },
+                Part::Field(Field::Name, _) => rinfo.name.clone(),
+                Part::Field(Field::Channel, _) => rinfo.channel.clone(),
+                Part::Field(Field::Folder, _) => folders.last().cloned().unwrap_or_default(),
+                Part::Field(Field::Id, _) => rinfo.id.to_string(),
+                Part::Field(Field::ProgramId, _) => rinfo.program_id.to_string(),
+                Part::Field(Field::Length, _) => rinfo.length.to_string(),
+                Part::Field(Field::StartTime, ref format) => format_time(&rinfo.start_time, format),
+                Part::Field(Field::Timestamp, ref format) => format_time(&rinfo.timestamp, format),
+                Part::Field(Field::EndTime, ref format) => {
+                    recording.map(|r| format_time(&r.end_time, format)).unwrap_or_default()
+                },
+                Part::Field(Field::ShortText, _) => recording.map(|r| r.short_text.clone()).unwrap_or_default(),
+            };
+            out.push_str(&self.sanitize(&value));
+        }
+        out
+    }
+    /// Makes `value` safe to use as a part of a file name
+    fn sanitize(&self, value: &str) -> String {
+        value.chars()
+            .flat_map(|c| {
+                let replaced = match c {
+                    '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => "_",
+                    c if c.is_control() => "_",
+                    c if self.options.ascii && !c.is_ascii() => transliterate(c),
+                    _ => ""
+                };
+                if replaced.is_empty() { vec![c] } else { replaced.chars().collect() }
+            })
+            .collect()
+    }
+}
+
+/// Returns the recordings of the tree under `root` with the names of the folders they are in,
+/// as `Namer::path` takes them
+pub fn recordings_with_folders(root: &Folder) -> Result<Vec<(RecordingInfo, Vec<String>)>, EVError> {
+    let walk = root.walk(WalkOptions {
+        on_error: ErrorPolicy::Abort,
+        max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        ..Default::default()
+    });
+    let mut folders: Vec<String> = Vec::new();
+    let mut recordings = Vec::new();
+    for entry in walk {
+        match entry {
+            // The folder the walk started from is at depth 0 and not part of the path
+            Entry::Folder(info, depth) => {
+                if depth > 0 {
+                    folders.truncate(depth - 1);
+                    folders.push(info.name);
+                }
+            },
+            Entry::Recording(rinfo) => recordings.push((rinfo, folders.clone())),
+            Entry::Failed(_, e) => return Err(e)
+        }
+    }
+    Ok(recordings)
+}
+
+/// Formats `timestamp` in Helsinki time, or returns it as Elisa Viihde sent it if it was not parsed
+fn format_time(timestamp: &Timestamp, format: &Option<String>) -> String {
+    let format = format.as_ref().map(|f| &f[..]).unwrap_or(DEFAULT_TIME_FORMAT);
+    timestamp.helsinki()
+        .and_then(|tm| time::strftime(format, &tm).ok())
+        .unwrap_or_else(|| timestamp.raw().to_string())
+}
+
+/// Returns the closest ASCII replacement of `c`
+fn transliterate(c: char) -> &'static str {
+    match c {
+        'ä' | 'å' | 'á' | 'à' | 'â' => "a",
+        'Ä' | 'Å' | 'Á' | 'À' | 'Â' => "A",
+        'ö' | 'ó' | 'ò' | 'ô' | 'ø' => "o",
+        'Ö' | 'Ó' | 'Ò' | 'Ô' | 'Ø' => "O",
+        'é' | 'è' | 'ê' | 'ë' => "e",
+        'É' | 'È' | 'Ê' | 'Ë' => "E",
+        'ü' | 'ú' | 'ù' => "u",
+        'Ü' | 'Ú' | 'Ù' => "U",
+        'í' | 'ì' | 'ï' => "i",
+        'Í' | 'Ì' | 'Ï' => "I",
+        'š' => "s",
+        'Š' => "S",
+        'ž' => "z",
+        'Ž' => "Z",
+        'ß' => "ss",
+        _ => "_"
+    }
+}
+
+fn collision_key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Collision, Namer, NamingOptions, Template};
+    use session::Session;
+    use std::default::Default;
+    use std::path::{Path, PathBuf};
+    use transport::FixtureTransport;
+    use types::RecordingInfo;
+
+    fn recording() -> RecordingInfo {
+        let mut rinfo = RecordingInfo::default();
+        rinfo.id = 7;
+        rinfo.name = "Tämä/tuo: osa 1".into();
+        rinfo.channel = "Yle TV1".into();
+        rinfo.start_time = "ma 17.03.2014 00:05".into();
+        rinfo
+    }
+
+    #[test]
+    fn renders_templates() {
+        let template: Template = "{folder}/{name} ({channel}, {start_time:%Y-%m-%d}).ts".parse().unwrap();
+        let mut namer = Namer::new(NamingOptions { template: template, ..Default::default() });
+        let path = namer.path(&recording(), &["Sarjat".to_string()]);
+        assert!(path == Path::new("Sarjat/Tämä_tuo_ osa 1 (Yle TV1, 2014-03-17).ts"));
+        let mut ascii = Namer::new(NamingOptions { ascii: true, mirror_folders: true, ..Default::default() });
+        let path = ascii.path(&recording(), &["Ääni".to_string(), "Kirjat".to_string()]);
+        assert!(path == Path::new("Aani/Kirjat/Tama_tuo_ osa 1 (7).ts"));
+        assert!("{nme}".parse::<Template>().is_err());
+        assert!("{name:%Y}".parse::<Template>().is_err());
+        assert!("{name".parse::<Template>().is_err());
+        assert!("{{{name}}}".parse::<Template>().unwrap().to_string() == "{{{name}}}");
+    }
+
+    #[test]
+    fn finds_the_extension_of_templates() {
+        assert!(Template::default().extension() == Some("ts"));
+        assert!("{folder}/{name}.mpeg.ts".parse::<Template>().unwrap().extension() == Some("ts"));
+        assert!("{name}".parse::<Template>().unwrap().extension().is_none());
+        assert!("{name}.".parse::<Template>().unwrap().extension().is_none());
+        assert!("Ep. 2/{name}".parse::<Template>().unwrap().extension().is_none());
+    }
+
+    #[test]
+    fn handles_collisions() {
+        let options = NamingOptions { template: "{channel}.ts".parse().unwrap(), ..Default::default() };
+        let mut namer = Namer::new(options.clone());
+        assert!(namer.path(&recording(), &[]) == Path::new("Yle TV1.ts"));
+        assert!(namer.path(&recording(), &[]) == Path::new("Yle TV1 (2).ts"));
+        let mut by_id = Namer::new(NamingOptions { on_collision: Collision::Id, ..options });
+        by_id.reserve(Path::new("yle tv1.ts"));
+        assert!(by_id.path(&recording(), &[]) == Path::new("Yle TV1 [7].ts"));
+        assert!(by_id.path(&recording(), &[]) == Path::new("Yle TV1 [7] (2).ts"));
+    }
+
+    #[test]
+    fn mirrors_the_folder_tree() {
+        let root = Session::with_transport(FixtureTransport::from_dir("testdata").unwrap()).root().unwrap();
+        let mut namer = Namer::new(NamingOptions { mirror_folders: true, ..Default::default() });
+        let mut paths: Vec<PathBuf> = namer.paths_for_tree(&root).unwrap().into_iter().map(|(_, p)| p).collect();
+        paths.sort();
+        assert!(paths.len() == 4);
+        assert!(paths[0] == Path::new("Foldername/Programname (100000003).ts"));
+        assert!(paths[1] == Path::new("Programname (100000001).ts"));
+        assert!(paths[3] == Path::new("Test folder/Programname (100000004).ts"));
+    }
+}
@@ -0,0 +1,125 @@
+/* vim: set et: */
+
+use types::{EVError, Recording};
+
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use time;
+
+/// Returns the path of the sidecar file of the media file at `media`
+/// `extension` is the extension the naming template gave the media file, see
+/// `Template::extension`. It is replaced with `.nfo`; otherwise `.nfo` is appended,
+/// so that `Ep. 2` gets `Ep. 2.nfo` instead of `Ep.nfo`.
+pub fn nfo_path(media: &Path, extension: Option<&str>) -> PathBuf {
+    match (extension, media.extension()) {
+        (Some(extension), Some(ext)) if ext == extension => media.with_extension("nfo"),
+        _ => {
+            let mut name = media.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+            name.push(".nfo");
+            media.with_file_name(name)
+        }
+    }
+}
+
+/// Returns the metadata of `recording` as a Kodi `.nfo` file, which Jellyfin and Emby read as well
+///
+/// The recording is described as a movie, since Elisa Viihde does not tell which
+/// recordings are episodes of a series. The air date is in Helsinki time.
+pub fn to_nfo(recording: &Recording) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<movie>\n");
+    element(&mut out, "title", &recording.name);
+    let plot = if recording.description.trim().is_empty() { &recording.short_text } else { &recording.description };
+    element(&mut out, "plot", plot);
+    element(&mut out, "outline", &recording.short_text);
+    element(&mut out, "studio", &recording.channel);
+    if let Some(tm) = recording.start_time.helsinki() {
+        if let Ok(date) = time::strftime("%Y-%m-%d", &tm) {
+            element(&mut out, "premiered", &date);
+            element(&mut out, "aired", &date);
+        }
+        element(&mut out, "year", &(tm.tm_year + 1900).to_string());
+    }
+    let minutes = recording.length.num_minutes();
+    if minutes > 0 {
+        element(&mut out, "runtime", &minutes.to_string());
+    }
+    let _ = writeln!(out, "  <uniqueid type=\"elisaviihde\" default=\"true\">{}</uniqueid>", recording.programviewid);
+    out.push_str("</movie>\n");
+    out
+}
+
+/// Writes the sidecar file of `recording` next to its media file at `media`
+/// Returns the path of the sidecar file, see `nfo_path`.
+pub fn write_nfo(recording: &Recording, media: &Path, extension: Option<&str>) -> Result<PathBuf, EVError> {
+    let path = nfo_path(media, extension);
+    try!(try!(File::create(&path)).write_all(to_nfo(recording).as_bytes()));
+    Ok(path)
+}
+
+/// Appends `<name>value</name>`, leaving out empty values
+fn element(out: &mut String, name: &str, value: &str) {
+    let value = value.trim();
+    if !value.is_empty() {
+        let _ = writeln!(out, "  <{}>{}</{}>", name, escape(value), name);
+    }
+}
+
+/// Escapes the characters that have a meaning in XML and drops the ones XML does not allow
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {},
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nfo_path, to_nfo};
+    use rustc_serialize::json;
+    use std::path::Path;
+    use types::Recording;
+
+    fn recording(description: &str) -> Recording {
+        json::decode(&format!("{{ \"id\": \"1\", \"name\": \"Tom%20%26%20Jerry\", \"channel\": \"MTV3\", \"scrambled_channel\": \"false\", \"short_text\": \"Lyhyesti\", \"description\": \"{}\", \"length\": \"25\", \"flength\": \"25 min\", \"start_time\": \"18.9.2013 23:35:00\", \"end_time\": \"19.9.2013 00:00:00\", \"has_started\": \"true\", \"has_ended\": \"true\", \"recorded\": \"true\", \"ready\": \"true\", \"is_wildcard\": \"false\", \"url\": \"http://google.fi\", \"programviewid\": \"123\", \"recordingid\": \"456\" }}", description)).unwrap()
+    }
+
+    #[test]
+    fn describes_the_recording() {
+        let nfo = to_nfo(&recording("%3Cb%3EJakso%3C%2Fb%3E"));
+        assert!(nfo.contains("<title>Tom &amp; Jerry</title>"));
+        assert!(nfo.contains("<plot>&lt;b&gt;Jakso&lt;/b&gt;</plot>"));
+        assert!(nfo.contains("<outline>Lyhyesti</outline>"));
+        assert!(nfo.contains("<studio>MTV3</studio>"));
+        assert!(nfo.contains("<aired>2013-09-18</aired>"));
+        assert!(nfo.contains("<year>2013</year>"));
+        assert!(nfo.contains("<runtime>25</runtime>"));
+        assert!(nfo.contains("<uniqueid type=\"elisaviihde\" default=\"true\">123</uniqueid>"));
+        assert!(nfo_path(Path::new("a/Tom & Jerry (1).ts"), Some("ts")) == Path::new("a/Tom & Jerry (1).nfo"));
+    }
+
+    #[test]
+    fn keeps_dots_that_do_not_start_an_extension() {
+        assert!(nfo_path(Path::new("a/Ep. 2"), None) == Path::new("a/Ep. 2.nfo"));
+        assert!(nfo_path(Path::new("a/Ep. 2"), Some("ts")) == Path::new("a/Ep. 2.nfo"));
+        assert!(nfo_path(Path::new("a/Tom & Jerry (1).ts"), None) == Path::new("a/Tom & Jerry (1).ts.nfo"));
+    }
+
+    #[test]
+    fn falls_back_to_the_short_text() {
+        let nfo = to_nfo(&recording(""));
+        assert!(nfo.contains("<plot>Lyhyesti</plot>"));
+    }
+}
@@ -0,0 +1,455 @@
+/* vim: set et: */
+
+use dedupe::Report;
+use pool::DEFAULT_MAX_IN_FLIGHT;
+use types::{EVError, Folder, FolderId, FolderInfo, RecordingInfo};
+use walk::{Entry, ErrorPolicy, WalkOptions};
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use std::collections::HashMap;
+use std::default::Default;
+use std::fmt;
+
+/// A single planned change in Elisa Viihde
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    /// Move the recording with `recording_id` to the folder `to`
+    Move {
+        recording_id: i32,
+        name: String,
+        from: FolderId,
+        to: FolderId,
+        destination_name: String,
+    },
+    /// Delete the recording with `recording_id` from the folder `from`
+    Delete {
+        recording_id: i32,
+        name: String,
+        from: FolderId,
+        /// Why the recording is deleted, for reviewing the plan
+        reason: String,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operation::Move { recording_id, ref name, from, to, ref destination_name } => {
+                write!(fmt, "move \"{}\" ({}) from folder {} to \"{}\" ({})", name, recording_id, from, destination_name, to)
+            },
+            Operation::Delete { recording_id, ref name, from, ref reason } => {
+                write!(fmt, "delete \"{}\" ({}) from folder {}: {}", name, recording_id, from, reason)
+            }
+        }
+    }
+}
+
+fn folder_id_from(id: i32) -> FolderId {
+    match id {
+        0 => FolderId::Root,
+        n => FolderId::FolderId(n)
+    }
+}
+
+fn folder_id_to(id: FolderId) -> i32 {
+    match id {
+        FolderId::Root => 0,
+        FolderId::FolderId(n) => n
+    }
+}
+
+fn folder_id_of(rinfo: &RecordingInfo) -> FolderId {
+    rinfo.folder_id.map(FolderId::FolderId).unwrap_or(FolderId::Root)
+}
+
+impl Encodable for Operation {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        match *self {
+            Operation::Move { recording_id, ref name, from, to, ref destination_name } => {
+                s.emit_struct("Operation", 6, |s| {
+                    try!(s.emit_struct_field("op", 0, |s| s.emit_str("move")));
+                    try!(s.emit_struct_field("recording_id", 1, |s| s.emit_i32(recording_id)));
+                    try!(s.emit_struct_field("name", 2, |s| s.emit_str(name)));
+                    try!(s.emit_struct_field("from", 3, |s| s.emit_i32(folder_id_to(from))));
+                    try!(s.emit_struct_field("to", 4, |s| s.emit_i32(folder_id_to(to))));
+                    s.emit_struct_field("destination_name", 5, |s| s.emit_str(destination_name))
+                })
+            },
+            Operation::Delete { recording_id, ref name, from, ref reason } => {
+                s.emit_struct("Operation", 5, |s| {
+                    try!(s.emit_struct_field("op", 0, |s| s.emit_str("delete")));
+                    try!(s.emit_struct_field("recording_id", 1, |s| s.emit_i32(recording_id)));
+                    try!(s.emit_struct_field("name", 2, |s| s.emit_str(name)));
+                    try!(s.emit_struct_field("from", 3, |s| s.emit_i32(folder_id_to(from))));
+                    s.emit_struct_field("reason", 4, |s| s.emit_str(reason))
+                })
+            }
+        }
+    }
+}
+
+impl Decodable for Operation {
+    fn decode<D : Decoder>(d: &mut D) -> Result<Operation, D::Error> {
+        d.read_struct("Operation", 0, |d| {
+            let op: String = json_field!("op", d);
+            match &op[..] {
+                "move" => Ok(Operation::Move {
+                    recording_id: json_field!("recording_id", d),
+                    name: json_field!("name", d),
+                    from: folder_id_from(json_field!("from", d)),
+                    to: folder_id_from(json_field!("to", d)),
+                    destination_name: json_field!("destination_name", d),
+                }),
+                "delete" => Ok(Operation::Delete {
+                    recording_id: json_field!("recording_id", d),
+                    name: json_field!("name", d),
+                    from: folder_id_from(json_field!("from", d)),
+                    reason: json_field!("reason", d),
+                }),
+                other => Err(d.error(&format!("Unknown operation: {}", other)))
+            }
+        })
+    }
+}
+
+/// A list of operations that can be reviewed before they are applied
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub operations: Vec<Operation>,
+}
+
+impl Default for Plan {
+    fn default() -> Plan {
+        Plan::new()
+    }
+}
+
+impl Plan {
+    /// Creates an empty plan
+    pub fn new() -> Plan {
+        Plan { operations: Vec::new() }
+    }
+    /// Plans moving the duplicates of `report` to `destination`
+    pub fn move_duplicates(report: &Report, destination: &FolderInfo) -> Plan {
+        let mut plan = Plan::new();
+        for rinfo in report.duplicates() {
+            plan.move_recording(rinfo, destination);
+        }
+        plan
+    }
+    /// Adds moving `rinfo` to `destination` to this plan
+    pub fn move_recording(&mut self, rinfo: &RecordingInfo, destination: &FolderInfo) {
+        self.operations.push(Operation::Move {
+            recording_id: rinfo.id,
+            name: rinfo.name.clone(),
+            from: folder_id_of(rinfo),
+            to: destination.id(),
+            destination_name: destination.name.clone(),
+        });
+    }
+    /// Adds deleting `rinfo` to this plan
+    pub fn delete_recording(&mut self, rinfo: &RecordingInfo, reason: &str) {
+        self.operations.push(Operation::Delete {
+            recording_id: rinfo.id,
+            name: rinfo.name.clone(),
+            from: folder_id_of(rinfo),
+            reason: reason.into(),
+        });
+    }
+    /// Returns the operations that are only in `self` or only in `other`
+    pub fn diff(&self, other: &Plan) -> PlanDiff {
+        PlanDiff {
+            added: other.operations.iter().filter(|op| !self.operations.contains(op)).cloned().collect(),
+            removed: self.operations.iter().filter(|op| !other.operations.contains(op)).cloned().collect(),
+        }
+    }
+    /// Applies this plan to the tree under `root`
+    /// The tree is read before applying, so moves that have already been applied are skipped.
+    /// Operations on recordings that are not in the tree fail, including deletes that were
+    /// applied before. A failing operation does not stop the rest of the plan from being applied.
+    pub fn apply(&self, root: &Folder) -> Result<ApplyReport, EVError> {
+        let mut index = Index { folders: HashMap::new(), recordings: HashMap::new() };
+        index.folders.insert(FolderId::Root, root.info().clone());
+        try!(index.add(root));
+        let results = self.operations.iter()
+            .map(|op| (op.clone(), index.apply(op)))
+            .collect();
+        Ok(ApplyReport { results: results })
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for op in self.operations.iter() {
+            try!(writeln!(fmt, "{}", op));
+        }
+        Ok(())
+    }
+}
+
+impl Encodable for Plan {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("Plan", 1, |s| {
+            s.emit_struct_field("operations", 0, |s| self.operations.encode(s))
+        })
+    }
+}
+
+impl Decodable for Plan {
+    fn decode<D : Decoder>(d: &mut D) -> Result<Plan, D::Error> {
+        d.read_struct("Plan", 0, |d| {
+            Ok(Plan { operations: json_field!("operations", d) })
+        })
+    }
+}
+
+/// Difference between two plans
+#[derive(Clone, Debug)]
+pub struct PlanDiff {
+    pub added: Vec<Operation>,
+    pub removed: Vec<Operation>,
+}
+
+impl fmt::Display for PlanDiff {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for op in self.removed.iter() {
+            try!(writeln!(fmt, "- {}", op));
+        }
+        for op in self.added.iter() {
+            try!(writeln!(fmt, "+ {}", op));
+        }
+        Ok(())
+    }
+}
+
+/// Result of applying a single operation
+#[derive(Debug)]
+pub enum Outcome {
+    Applied,
+    /// The operation had already been applied, nothing was done
+    AlreadyApplied,
+    Failed(EVError),
+}
+
+/// Per-operation results of applying a plan
+#[derive(Debug)]
+pub struct ApplyReport {
+    pub results: Vec<(Operation, Outcome)>,
+}
+
+impl ApplyReport {
+    /// Returns true if every operation of the plan is in effect
+    pub fn is_complete(&self) -> bool {
+        self.results.iter().all(|&(_, ref outcome)| !matches!(*outcome, Outcome::Failed(_)))
+    }
+    /// Returns a plan of the operations that failed, for resuming the apply later
+    pub fn remaining(&self) -> Plan {
+        Plan {
+            operations: self.results.iter()
+                .filter(|&&(_, ref outcome)| matches!(*outcome, Outcome::Failed(_)))
+                .map(|&(ref op, _)| op.clone())
+                .collect()
+        }
+    }
+}
+
+struct Index {
+    folders: HashMap<FolderId, FolderInfo>,
+    recordings: HashMap<i32, RecordingInfo>,
+}
+
+impl Index {
+    fn add(&mut self, folder: &Folder) -> Result<(), EVError> {
+        let walk = folder.walk(WalkOptions {
+            on_error: ErrorPolicy::Abort,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            ..Default::default()
+        });
+        for entry in walk {
+            match entry {
+                Entry::Folder(finfo, _) => { self.folders.insert(finfo.id(), finfo); },
+                Entry::Recording(rinfo) => { self.recordings.insert(rinfo.id, rinfo); },
+                Entry::Failed(_, e) => return Err(e)
+            }
+        }
+        Ok(())
+    }
+    fn apply(&mut self, op: &Operation) -> Outcome {
+        match *op {
+            Operation::Move { recording_id, to, .. } => {
+                let destination = match self.folders.get(&to) {
+                    Some(finfo) => finfo.clone(),
+                    None => return Outcome::Failed(EVError::NotFound)
+                };
+                let rinfo = match self.recordings.get(&recording_id) {
+                    Some(rinfo) => rinfo.clone(),
+                    None => return Outcome::Failed(EVError::NotFound)
+                };
+                if folder_id_of(&rinfo) == to {
+                    return Outcome::AlreadyApplied;
+                }
+                match rinfo.move_to(&destination) {
+                    Ok(moved) => {
+                        self.recordings.insert(recording_id, moved);
+                        Outcome::Applied
+                    },
+                    Err(e) => Outcome::Failed(e)
+                }
+            },
+            Operation::Delete { recording_id, .. } => {
+                // A recording missing from the tree may as well have a mistyped id or be
+                // under another folder, so it is not taken as deleted
+                let rinfo = match self.recordings.remove(&recording_id) {
+                    Some(rinfo) => rinfo,
+                    None => return Outcome::Failed(EVError::NotFound)
+                };
+                match rinfo.delete() {
+                    Ok(()) => Outcome::Applied,
+                    Err(e) => {
+                        self.recordings.insert(recording_id, rinfo);
+                        Outcome::Failed(e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Operation, Outcome, Plan};
+    use session::Session;
+    use std::sync::Arc;
+    use traits::Fetch;
+    use transport::FixtureTransport;
+    use types::{EVError, Folder, FolderId, ProgramId};
+    use urls::EVUrl;
+    use rustc_serialize::json;
+
+    fn root() -> Folder {
+        Session::with_transport(FixtureTransport::from_dir("testdata").unwrap()).root().unwrap()
+    }
+
+    /// Returns the test data with Elisa Viihde accepting `commands`
+    fn transport(commands: &[EVUrl]) -> Arc<FixtureTransport> {
+        let transport = Arc::new(FixtureTransport::from_dir("testdata").unwrap());
+        for url in commands {
+            transport.insert(url.clone(), "TRUE");
+        }
+        transport
+    }
+
+    /// Returns the urls of the moves and deletes sent through `transport`
+    fn commands(transport: &FixtureTransport) -> Vec<EVUrl> {
+        transport.requests().into_iter()
+            .map(|r| r.url)
+            .filter(|url| matches!(*url, EVUrl::Move(..) | EVUrl::Delete(..)))
+            .collect()
+    }
+
+    fn plan(root: &Folder) -> Plan {
+        let destination = root.find_by_name("Test folder").unwrap();
+        let mut plan = Plan::new();
+        for rinfo in root.recordings() {
+            plan.move_recording(rinfo, &destination);
+        }
+        plan
+    }
+
+    #[test]
+    fn plan_survives_serialisation() {
+        let root = root();
+        let mut plan = plan(&root);
+        plan.delete_recording(root.recordings().next().unwrap(), "too old");
+        assert!(plan.operations.len() == 3);
+        let encoded = json::encode(&plan).unwrap();
+        let decoded: Plan = json::decode(&encoded).unwrap();
+        assert!(decoded == plan);
+    }
+
+    #[test]
+    fn diff_shows_changed_operations() {
+        let root = root();
+        let full = plan(&root);
+        let mut partial = full.clone();
+        partial.operations.pop();
+        let diff = partial.diff(&full);
+        assert!(diff.removed.is_empty());
+        assert!(diff.added.len() == 1);
+        assert!(diff.to_string().starts_with("+ move \"Programname2\""));
+    }
+
+    #[test]
+    fn apply_reports_every_operation() {
+        let moves = vec![
+            EVUrl::Move(ProgramId::ProgramId(123456789), FolderId::FolderId(1000002)),
+            EVUrl::Move(ProgramId::ProgramId(123456790), FolderId::FolderId(1000002)),
+        ];
+        let transport = transport(&moves);
+        let root = Session::with_transport(transport.clone()).root().unwrap();
+        let mut plan = plan(&root);
+        plan.operations.push(Operation::Move {
+            recording_id: 1,
+            name: "Does not exist".into(),
+            from: FolderId::Root,
+            to: FolderId::FolderId(1000002),
+            destination_name: "Test folder".into(),
+        });
+        let report = plan.apply(&root).unwrap();
+        assert!(report.results.len() == 3);
+        match report.results[0].1 {
+            Outcome::Applied => {},
+            _ => assert!(false, "Move should have been applied")
+        }
+        match report.results[2].1 {
+            Outcome::Failed(_) => {},
+            _ => assert!(false, "Moving a missing recording should fail")
+        }
+        assert!(!report.is_complete());
+        assert!(report.remaining().operations.len() == 1);
+        assert!(commands(&transport) == moves);
+    }
+
+    #[test]
+    fn apply_deletes_recordings() {
+        let delete = EVUrl::Delete(ProgramId::ProgramId(123456789));
+        let transport = transport(&[delete.clone()]);
+        let root = Session::with_transport(transport.clone()).root().unwrap();
+        let mut plan = Plan::new();
+        let rinfo = root.recordings().next().unwrap();
+        plan.delete_recording(rinfo, "too old");
+        plan.operations.push(Operation::Delete {
+            recording_id: 1,
+            name: "Does not exist".into(),
+            from: FolderId::Root,
+            reason: "too old".into(),
+        });
+        assert!(plan.to_string().starts_with("delete \"Programname\" (100000001) from folder 0: too old"));
+        let report = plan.apply(&root).unwrap();
+        match report.results[0].1 {
+            Outcome::Applied => {},
+            _ => assert!(false, "Delete should have been applied")
+        }
+        match report.results[1].1 {
+            Outcome::Failed(EVError::NotFound) => {},
+            _ => assert!(false, "Deleting a missing recording should fail")
+        }
+        assert!(!report.is_complete());
+        assert!(report.remaining().operations.len() == 1);
+        assert!(commands(&transport) == vec![delete]);
+    }
+
+    #[test]
+    fn applied_operations_are_skipped() {
+        let root = root();
+        let mut plan = Plan::new();
+        let destination = root.find_by_name("Foldername").unwrap();
+        let folder = destination.fetch().unwrap();
+        let rinfo = folder.recordings().next().unwrap();
+        plan.move_recording(rinfo, &destination);
+        match plan.apply(&root).unwrap().results[0].1 {
+            Outcome::AlreadyApplied => {},
+            _ => assert!(false, "Move should have been skipped")
+        }
+    }
+}
@@ -0,0 +1,250 @@
+/* vim: set et: */
+
+use traits::Fetch;
+use types::{EVError, Folder, FolderInfo};
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Number of concurrent requests used when fetching a tree in parallel
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 4;
+
+type Loaded = (FolderInfo, Result<Folder, EVError>);
+
+/// Fetches folders on a bounded number of worker threads
+///
+/// At most `max_in_flight` folders are being fetched at any time, the rest wait
+/// in a queue. Dropping the pool or calling `cancel` discards the queued folders,
+/// so no new requests are sent after the caller has what it needs; the workers
+/// exit as soon as their current request finishes.
+pub struct FetchPool {
+    max_in_flight: usize,
+    queue: VecDeque<FolderInfo>,
+    in_flight: usize,
+    workers: usize,
+    jobs: Option<Sender<FolderInfo>>,
+    shared_jobs: Arc<Mutex<Receiver<FolderInfo>>>,
+    results_tx: Sender<Loaded>,
+    results: Receiver<Loaded>,
+}
+
+impl FetchPool {
+    /// Creates a pool that fetches at most `max_in_flight` folders at a time
+    /// Worker threads are started on demand.
+    pub fn new(max_in_flight: usize) -> FetchPool {
+        let (jobs_tx, jobs_rx) = channel();
+        let (results_tx, results_rx) = channel();
+        FetchPool {
+            max_in_flight: if max_in_flight > 0 { max_in_flight } else { 1 },
+            queue: VecDeque::new(),
+            in_flight: 0,
+            workers: 0,
+            jobs: Some(jobs_tx),
+            shared_jobs: Arc::new(Mutex::new(jobs_rx)),
+            results_tx: results_tx,
+            results: results_rx,
+        }
+    }
+    /// Queues `finfo` to be fetched
+    pub fn submit(&mut self, finfo: FolderInfo) {
+        self.queue.push_back(finfo);
+        self.dispatch();
+    }
+    /// Returns the number of folders that are queued or being fetched
+    pub fn pending(&self) -> usize {
+        self.queue.len() + self.in_flight
+    }
+    /// Waits for the next folder to be fetched
+    /// Folders are returned in the order their requests finish. A folder whose worker
+    /// panicked is returned with `EVError::WorkerPanicked`.
+    /// Returns `None` when there are no folders left to fetch.
+    pub fn next_loaded(&mut self) -> Option<(FolderInfo, Result<Folder, EVError>)> {
+        self.dispatch();
+        if self.in_flight == 0 || self.jobs.is_none() {
+            return None;
+        }
+        let loaded = self.results.recv().ok();
+        self.in_flight -= 1;
+        if let Some((_, Err(EVError::WorkerPanicked))) = loaded {
+            self.workers -= 1;
+        }
+        self.dispatch();
+        loaded
+    }
+    /// Discards the queued folders and stops the workers
+    /// Requests that are already being sent are finished, but their results are dropped.
+    pub fn cancel(&mut self) {
+        self.queue.clear();
+        self.in_flight = 0;
+        self.jobs = None;
+    }
+    fn dispatch(&mut self) {
+        while self.in_flight < self.max_in_flight && self.jobs.is_some() {
+            let finfo = match self.queue.pop_front() {
+                Some(finfo) => finfo,
+                None => return
+            };
+            if self.workers <= self.in_flight {
+                self.spawn_worker();
+            }
+            if let Some(ref jobs) = self.jobs {
+                if jobs.send(finfo).is_ok() {
+                    self.in_flight += 1;
+                }
+            }
+        }
+    }
+    fn spawn_worker(&mut self) {
+        let jobs = self.shared_jobs.clone();
+        let results = self.results_tx.clone();
+        self.workers += 1;
+        thread::spawn(move || {
+            loop {
+                let finfo = match jobs.lock().unwrap().recv() {
+                    Ok(finfo) => finfo,
+                    Err(_) => return
+                };
+                let mut job = Job { finfo: Some(finfo), results: results.clone() };
+                let folder = job.finfo.as_ref().unwrap().fetch();
+                let finfo = job.finfo.take().unwrap();
+                if results.send((finfo, folder)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Folder being fetched by a worker
+/// If the worker panics, the folder is reported as failed so that `next_loaded` does not
+/// wait for it forever.
+struct Job {
+    finfo: Option<FolderInfo>,
+    results: Sender<Loaded>,
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        if let Some(finfo) = self.finfo.take() {
+            let _ = self.results.send((finfo, Err(EVError::WorkerPanicked)));
+        }
+    }
+}
+
+impl Drop for FetchPool {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FetchPool;
+    use session::Session;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use transport::{FixtureTransport, Request, Response, Transport};
+    use types::{EVError, FolderId};
+    use urls::EVUrl;
+
+    /// Holds the responses to the first pages of folders until the test lets them through
+    struct GatedTransport {
+        fixtures: FixtureTransport,
+        permits: Mutex<Receiver<()>>,
+    }
+
+    impl Transport for GatedTransport {
+        fn send(&self, request: &Request) -> Result<Response, EVError> {
+            let response = self.fixtures.send(request);
+            if let EVUrl::Folder(FolderId::FolderId(_)) = request.url {
+                try!(self.permits.lock().unwrap().recv());
+            }
+            response
+        }
+    }
+
+    /// Panics instead of answering for the folder `Foldername`
+    struct PanickingTransport {
+        fixtures: FixtureTransport,
+    }
+
+    impl Transport for PanickingTransport {
+        fn send(&self, request: &Request) -> Result<Response, EVError> {
+            if request.url == EVUrl::Folder(FolderId::FolderId(1000001)) {
+                panic!("Foldername can not be fetched");
+            }
+            self.fixtures.send(request)
+        }
+    }
+
+    #[test]
+    fn fetches_every_submitted_folder() {
+        let root = Session::with_transport(FixtureTransport::from_dir("testdata").unwrap()).root().unwrap();
+        let mut pool = FetchPool::new(1);
+        for finfo in root.folders() {
+            pool.submit(finfo.clone());
+        }
+        assert!(pool.pending() == 2);
+        let mut names = Vec::new();
+        while let Some((finfo, folder)) = pool.next_loaded() {
+            assert!(folder.is_ok());
+            names.push(finfo.name);
+        }
+        names.sort();
+        assert!(names == vec!["Foldername".to_string(), "Test folder".to_string()]);
+        assert!(pool.pending() == 0);
+    }
+
+    #[test]
+    fn cancelled_pool_sends_no_more_requests() {
+        let (permits, gate) = channel();
+        let transport = Arc::new(GatedTransport {
+            fixtures: FixtureTransport::from_dir("testdata").unwrap(),
+            permits: Mutex::new(gate),
+        });
+        let root = Session::with_transport(transport.clone()).root().unwrap();
+        let mut pool = FetchPool::new(1);
+        for _ in 0..3 {
+            for finfo in root.folders() {
+                pool.submit(finfo.clone());
+            }
+        }
+        permits.send(()).unwrap();
+        assert!(pool.next_loaded().is_some());
+        // The next folder is in flight until it gets a permit
+        pool.cancel();
+        assert!(pool.pending() == 0);
+        assert!(pool.next_loaded().is_none());
+        permits.send(()).unwrap();
+        drop(permits);
+        drop(pool);
+        drop(root);
+        // The transport is shared with the workers until they have exited
+        while Arc::strong_count(&transport) > 1 {
+            thread::yield_now();
+        }
+        let folders = transport.fixtures.requests().iter()
+            .filter(|r| match r.url { EVUrl::Folder(FolderId::FolderId(_)) => true, _ => false })
+            .count();
+        assert!(folders == 2);
+    }
+
+    #[test]
+    fn panicking_workers_are_reported() {
+        let transport = PanickingTransport { fixtures: FixtureTransport::from_dir("testdata").unwrap() };
+        let root = Session::with_transport(transport).root().unwrap();
+        let mut pool = FetchPool::new(1);
+        for finfo in root.folders() {
+            pool.submit(finfo.clone());
+        }
+        let mut loaded = Vec::new();
+        while let Some((finfo, folder)) = pool.next_loaded() {
+            loaded.push((finfo.name, folder.is_ok()));
+        }
+        loaded.sort();
+        assert!(loaded == vec![("Foldername".to_string(), false), ("Test folder".to_string(), true)]);
+    }
+}
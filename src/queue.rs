@@ -0,0 +1,391 @@
+/* vim: set et: */
+
+use download::{Downloader, Progress};
+use naming::{self, Namer, NamingOptions};
+use nfo;
+use session::Session;
+use traits::Fetch;
+use types::{EVError, Folder, RecordingInfo};
+
+use rustc_serialize::{json, Decodable, Decoder, Encodable, Encoder};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::thread;
+
+/// Number of recordings downloaded at a time by default
+pub const DEFAULT_MAX_DOWNLOADS: usize = 2;
+
+/// State of a queued download
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    Pending,
+    /// Downloaded with the size of the file
+    Done(u64),
+    /// Downloading failed with the description of the error
+    Failed(String),
+}
+
+/// Recording in a `DownloadQueue`
+/// Only what is needed to fetch the recording again is stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueEntry {
+    pub recording_id: i32,
+    pub program_id: i32,
+    pub name: String,
+    /// File the recording is downloaded to
+    pub path: PathBuf,
+    pub status: Status,
+}
+
+impl QueueEntry {
+    /// Returns true if the file of a downloaded entry still has the downloaded size
+    fn is_present(&self) -> bool {
+        match self.status {
+            Status::Done(size) => fs::metadata(&self.path).map(|m| m.len() == size).unwrap_or(false),
+            _ => false
+        }
+    }
+    /// Returns the recording to fetch the details of the entry with
+    fn recording(&self, session: &Session) -> RecordingInfo {
+        let mut rinfo = RecordingInfo::default();
+        rinfo.id = self.recording_id;
+        rinfo.program_id = self.program_id;
+        rinfo.name = self.name.clone();
+        rinfo.bind(session);
+        rinfo
+    }
+}
+
+impl Encodable for QueueEntry {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let (status, size, error) = match self.status {
+            Status::Pending => ("pending", None, None),
+            Status::Done(size) => ("done", Some(size), None),
+            Status::Failed(ref e) => ("failed", None, Some(e.clone())),
+        };
+        s.emit_struct("QueueEntry", 7, |s| {
+            try!(s.emit_struct_field("recording_id", 0, |s| s.emit_i32(self.recording_id)));
+            try!(s.emit_struct_field("program_id", 1, |s| s.emit_i32(self.program_id)));
+            try!(s.emit_struct_field("name", 2, |s| s.emit_str(&self.name)));
+            try!(s.emit_struct_field("path", 3, |s| s.emit_str(&self.path.to_string_lossy())));
+            try!(s.emit_struct_field("status", 4, |s| s.emit_str(status)));
+            try!(s.emit_struct_field("size", 5, |s| size.encode(s)));
+            s.emit_struct_field("error", 6, |s| error.encode(s))
+        })
+    }
+}
+
+impl Decodable for QueueEntry {
+    fn decode<D : Decoder>(d: &mut D) -> Result<QueueEntry, D::Error> {
+        d.read_struct("QueueEntry", 0, |d| {
+            let path: String = json_field!("path", d);
+            let status: String = json_field!("status", d);
+            let size: Option<u64> = json_field!("size", d);
+            let error: Option<String> = json_field!("error", d);
+            let status = match (&status[..], size) {
+                ("pending", _) => Status::Pending,
+                ("done", Some(size)) => Status::Done(size),
+                ("failed", _) => Status::Failed(error.unwrap_or_default()),
+                (other, _) => return Err(d.error(&format!("Unknown status: {}", other)))
+            };
+            Ok(QueueEntry {
+                recording_id: json_field!("recording_id", d),
+                program_id: json_field!("program_id", d),
+                name: json_field!("name", d),
+                path: PathBuf::from(path),
+                status: status,
+            })
+        })
+    }
+}
+
+/// Queue of recordings to download that is saved to disk after every change
+///
+/// The recordings are downloaded to a directory, with the details of each recording
+/// fetched just before it is downloaded. When a run is interrupted, opening the queue
+/// from the same file continues where it left off: downloaded files are skipped and
+/// partial ones are resumed.
+pub struct DownloadQueue {
+    state: PathBuf,
+    dir: PathBuf,
+    session: Session,
+    max_downloads: usize,
+    write_nfo: bool,
+    namer: Namer,
+    entries: Vec<QueueEntry>,
+}
+
+/// Message from a download worker
+enum Event {
+    Progress(usize, Progress),
+    Finished(usize, Result<u64, EVError>),
+}
+
+impl DownloadQueue {
+    /// Opens the queue saved to `state`, or creates an empty one if it does not exist
+    /// The recordings are downloaded to `dir` and fetched through `session`.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(state: P, dir: Q, session: &Session) -> Result<DownloadQueue, EVError> {
+        let entries = if state.as_ref().exists() {
+            let mut s = String::new();
+            try!(try!(File::open(&state)).read_to_string(&mut s));
+            try!(json::decode(&s))
+        }
+        else {
+            Vec::new()
+        };
+        let mut queue = DownloadQueue {
+            state: state.as_ref().to_path_buf(),
+            dir: dir.as_ref().to_path_buf(),
+            session: session.clone(),
+            max_downloads: DEFAULT_MAX_DOWNLOADS,
+            write_nfo: false,
+            namer: Namer::new(Default::default()),
+            entries: entries,
+        };
+        queue.reserve_paths();
+        Ok(queue)
+    }
+    /// Sets how the files of the recordings queued from now on are named
+    pub fn set_naming(&mut self, options: NamingOptions) {
+        self.namer = Namer::new(options);
+        self.reserve_paths();
+    }
+    /// Sets the number of recordings downloaded at a time
+    pub fn set_max_downloads(&mut self, max_downloads: usize) {
+        self.max_downloads = if max_downloads > 0 { max_downloads } else { 1 };
+    }
+    /// Sets whether a `.nfo` sidecar file for media centres is written next to each download
+    /// See `nfo::to_nfo`.
+    pub fn set_write_nfo(&mut self, write_nfo: bool) {
+        self.write_nfo = write_nfo;
+    }
+    /// Returns the queued recordings in the order they were added
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.entries
+    }
+    /// Returns the number of recordings waiting to be downloaded
+    pub fn pending(&self) -> usize {
+        self.entries.iter().filter(|e| e.status == Status::Pending).count()
+    }
+    /// Adds `rinfo` to the queue
+    /// Its folder is not known, so the file is not put in a mirrored folder directory.
+    /// Returns false if the recording was already queued.
+    pub fn enqueue(&mut self, rinfo: &RecordingInfo) -> Result<bool, EVError> {
+        let added = self.add(rinfo, &[]);
+        if added {
+            try!(self.save());
+        }
+        Ok(added)
+    }
+    /// Adds the recordings of the tree under `folder` to the queue
+    /// Returns the number of recordings that were not queued before.
+    pub fn enqueue_folder(&mut self, folder: &Folder) -> Result<usize, EVError> {
+        let mut added = 0;
+        for (rinfo, folders) in try!(naming::recordings_with_folders(folder)) {
+            if self.add(&rinfo, &folders) {
+                added += 1;
+            }
+        }
+        try!(self.save());
+        Ok(added)
+    }
+    /// Queues the failed recordings again
+    pub fn retry_failed(&mut self) -> Result<(), EVError> {
+        for entry in self.entries.iter_mut() {
+            if let Status::Failed(_) = entry.status {
+                entry.status = Status::Pending;
+            }
+        }
+        self.save()
+    }
+    /// Downloads the pending recordings with `downloader`
+    /// Recordings whose files were removed or changed after downloading are downloaded again.
+    /// `progress` is called with the entry being downloaded. A recording that fails is
+    /// marked as failed and the rest are still downloaded; only failing to save the queue
+    /// is returned as an error.
+    pub fn run<F>(&mut self, downloader: &Downloader, mut progress: F) -> Result<(), EVError>
+        where F: FnMut(&QueueEntry, &Progress) {
+        for entry in self.entries.iter_mut() {
+            if let Status::Done(_) = entry.status {
+                if !entry.is_present() {
+                    entry.status = Status::Pending;
+                }
+            }
+        }
+        try!(self.save());
+        let jobs: Mutex<VecDeque<(usize, QueueEntry)>> = Mutex::new(self.entries.iter()
+            .enumerate()
+            .filter(|&(_, e)| e.status == Status::Pending)
+            .map(|(idx, e)| (idx, e.clone()))
+            .collect());
+        let workers = self.max_downloads.min(jobs.lock().unwrap().len());
+        let session = self.session.clone();
+        let write_nfo = self.write_nfo;
+        let extension = self.namer.options().template.extension().map(|ext| ext.to_string());
+        let (events_tx, events) = channel();
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let events_tx = events_tx.clone();
+                let jobs = &jobs;
+                let session = &session;
+                let extension = &extension;
+                scope.spawn(move || {
+                    loop {
+                        let (idx, entry) = match jobs.lock().unwrap().pop_front() {
+                            Some(job) => job,
+                            None => return
+                        };
+                        let result = create_parent(&entry.path).and_then(|_| entry.recording(session).fetch_into()).and_then(|rec| {
+                            let size = try!(downloader.download_recording(&rec, &entry.path, |p| {
+                                let _ = events_tx.send(Event::Progress(idx, *p));
+                            }));
+                            if write_nfo {
+                                try!(nfo::write_nfo(&rec, &entry.path, extension.as_ref().map(|ext| &ext[..])));
+                            }
+                            Ok(size)
+                        });
+                        if events_tx.send(Event::Finished(idx, result)).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+            drop(events_tx);
+            for event in events {
+                match event {
+                    Event::Progress(idx, p) => progress(&self.entries[idx], &p),
+                    Event::Finished(idx, result) => {
+                        self.entries[idx].status = match result {
+                            Ok(size) => Status::Done(size),
+                            Err(e) => Status::Failed(e.to_string())
+                        };
+                        if let Err(e) = self.save() {
+                            // Stop handing out downloads that could not be recorded
+                            jobs.lock().unwrap().clear();
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+    fn add(&mut self, rinfo: &RecordingInfo, folders: &[String]) -> bool {
+        if self.entries.iter().any(|e| e.recording_id == rinfo.id) {
+            return false;
+        }
+        let path = self.dir.join(self.namer.path(rinfo, folders));
+        self.entries.push(QueueEntry {
+            recording_id: rinfo.id,
+            program_id: rinfo.program_id,
+            name: rinfo.name.clone(),
+            path: path,
+            status: Status::Pending,
+        });
+        true
+    }
+    /// Keeps new recordings from being named like the queued ones
+    fn reserve_paths(&mut self) {
+        for entry in self.entries.iter() {
+            if let Ok(path) = entry.path.strip_prefix(&self.dir) {
+                self.namer.reserve(path);
+            }
+        }
+    }
+    /// Writes the queue to a temporary file and moves it over the saved one,
+    /// so an interrupted save does not lose the queue
+    fn save(&self) -> Result<(), EVError> {
+        let encoded = try!(json::encode(&self.entries).map_err(|e| EVError::UnexpectedShape(e.to_string())));
+        let tmp = self.state.with_extension("tmp");
+        try!(try!(File::create(&tmp)).write_all(encoded.as_bytes()));
+        try!(fs::rename(&tmp, &self.state));
+        Ok(())
+    }
+}
+
+/// Creates the directories of `path`, which the naming options may have added
+fn create_parent(path: &Path) -> Result<(), EVError> {
+    match path.parent() {
+        Some(dir) => fs::create_dir_all(dir).map_err(EVError::from),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DownloadQueue, Status};
+    use download::{Body, DownloadOptions, Downloader, Progress, Source};
+    use hyper::status::StatusCode;
+    use naming::NamingOptions;
+    use nfo;
+    use session::Session;
+    use std::default::Default;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use transport::FixtureTransport;
+    use types::EVError;
+    use url::Url;
+
+    const MEDIA: &'static [u8] = b"media of a recording";
+
+    #[derive(Clone)]
+    struct CountingSource {
+        requests: Arc<Mutex<usize>>,
+    }
+
+    impl Source for CountingSource {
+        fn open(&self, _: &Url, offset: u64) -> Result<Body, EVError> {
+            *self.requests.lock().unwrap() += 1;
+            let total = MEDIA.len() as u64;
+            if offset >= total {
+                return Ok(Body { status: StatusCode::RangeNotSatisfiable, offset: 0, total: Some(total), reader: Box::new(io::empty()) });
+            }
+            Ok(Body {
+                status: StatusCode::PartialContent,
+                offset: offset,
+                total: Some(total),
+                reader: Box::new(io::Cursor::new(MEDIA[offset as usize..].to_vec())),
+            })
+        }
+    }
+
+    #[test]
+    fn continues_where_it_left_off() {
+        let dir = env::temp_dir().join(format!("evimproved-queue-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let state = dir.join("queue.json");
+        let session = Session::with_transport(FixtureTransport::from_dir("testdata").unwrap());
+        let source = CountingSource { requests: Arc::new(Mutex::new(0)) };
+        let downloader = Downloader::with_source(source.clone(), DownloadOptions::default());
+
+        let mut queue = DownloadQueue::open(&state, &dir, &session).unwrap();
+        queue.set_naming(NamingOptions { mirror_folders: true, ..Default::default() });
+        queue.set_write_nfo(true);
+        let root = session.root().unwrap();
+        assert!(queue.enqueue_folder(&root).unwrap() == 4);
+        assert!(queue.entries().iter().any(|e| e.path == dir.join("Foldername").join("Programname (100000003).ts")));
+        assert!(!queue.enqueue(root.recordings().next().unwrap()).unwrap());
+        let mut progressed = 0;
+        queue.run(&downloader, |_, _: &Progress| progressed += 1).unwrap();
+        assert!(progressed == 4);
+        let done = queue.entries().iter().filter(|e| e.status == Status::Done(MEDIA.len() as u64)).count();
+        assert!(done == 4);
+        assert!(nfo::nfo_path(&queue.entries()[0].path, Some("ts")).exists());
+        assert!(queue.pending() == 0);
+
+        let mut reopened = DownloadQueue::open(&state, &dir, &session).unwrap();
+        assert!(reopened.entries() == queue.entries());
+        reopened.retry_failed().unwrap();
+        fs::remove_file(&reopened.entries()[0].path).unwrap();
+        reopened.run(&downloader, |_, _: &Progress| {}).unwrap();
+        assert!(reopened.entries()[0].status == Status::Done(MEDIA.len() as u64));
+        assert!(*source.requests.lock().unwrap() == 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
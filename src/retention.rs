@@ -0,0 +1,269 @@
+/* vim: set et: */
+
+use plan::Plan;
+use pool::DEFAULT_MAX_IN_FLIGHT;
+use types::{EVError, Folder, FolderId, RecordingInfo};
+use walk::{ErrorPolicy, WalkOptions};
+
+use rustc_serialize::{Decodable, Decoder};
+use std::collections::{HashMap, HashSet};
+use std::default::Default;
+use std::fmt;
+use time::{self, Timespec};
+
+const DAY: i64 = 24 * 3600;
+
+/// Declarative rule selecting recordings to delete
+///
+/// A recording matches the rule when it matches every condition that is set.
+/// A rule without any conditions matches no recordings, so a rule can not delete
+/// the whole library by accident. When `keep_newest` is set, the matching recordings
+/// are grouped by name and all but the newest ones of each group are deleted.
+///
+/// Rules can be decoded from JSON, with the fields that are not set left out:
+///
+/// ```json
+/// {"channel": "Yle TV2", "older_than_days": 30, "watched": true}
+/// {"name": "Uutiset", "keep_newest": 5}
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rule {
+    /// Name of the channel, compared case-insensitively
+    pub channel: Option<String>,
+    /// Name of the programme, compared case-insensitively
+    pub name: Option<String>,
+    /// Part of the name of the programme, compared case-insensitively
+    pub name_contains: Option<String>,
+    pub folder: Option<FolderId>,
+    /// Minimum age of the recording by its `timestamp`
+    /// Recordings whose timestamp could not be parsed are never older than this.
+    pub older_than_days: Option<i64>,
+    /// Whether the recording has been watched, that is its `viewcount` is not 0
+    pub watched: Option<bool>,
+    /// Number of the newest recordings of each programme to keep
+    pub keep_newest: Option<usize>,
+}
+
+impl Rule {
+    fn has_conditions(&self) -> bool {
+        self.channel.is_some() || self.name.is_some() || self.name_contains.is_some() ||
+            self.folder.is_some() || self.older_than_days.is_some() || self.watched.is_some()
+    }
+    /// Returns true if `rinfo` matches all conditions of this rule at `now`
+    pub fn matches(&self, rinfo: &RecordingInfo, now: Timespec) -> bool {
+        if !self.has_conditions() {
+            return false;
+        }
+        let folder = rinfo.folder_id.map(FolderId::FolderId).unwrap_or(FolderId::Root);
+        self.channel.as_ref().map_or(true, |c| c.to_lowercase() == rinfo.channel.to_lowercase()) &&
+            self.name.as_ref().map_or(true, |n| n.trim().to_lowercase() == programme(rinfo)) &&
+            self.name_contains.as_ref().map_or(true, |n| programme(rinfo).contains(&n.to_lowercase()[..])) &&
+            self.folder.map_or(true, |f| f == folder) &&
+            self.older_than_days.map_or(true, |days| {
+                rinfo.timestamp.timespec().map_or(false, |t| t.sec <= now.sec - days * DAY)
+            }) &&
+            self.watched.map_or(true, |watched| watched == (rinfo.viewcount > 0))
+    }
+    /// Returns the recordings of `recordings` this rule deletes at `now`
+    pub fn select<'a>(&self, recordings: &'a [RecordingInfo], now: Timespec) -> Vec<&'a RecordingInfo> {
+        let matching = recordings.iter().filter(|r| self.matches(r, now));
+        let keep = match self.keep_newest {
+            Some(keep) => keep,
+            None => return matching.collect()
+        };
+        let mut programmes: HashMap<String, Vec<&RecordingInfo>> = HashMap::new();
+        for rinfo in matching {
+            // Recordings that can not be ordered are kept
+            if rinfo.timestamp.timespec().is_some() {
+                programmes.entry(programme(rinfo)).or_default().push(rinfo);
+            }
+        }
+        let mut selected = Vec::new();
+        for (_, mut episodes) in programmes {
+            episodes.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+            selected.extend(episodes.into_iter().skip(keep));
+        }
+        selected.sort_by_key(|r| r.id);
+        selected
+    }
+}
+
+fn programme(rinfo: &RecordingInfo) -> String {
+    rinfo.name.trim().to_lowercase()
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut conditions = Vec::new();
+        if let Some(ref channel) = self.channel {
+            conditions.push(format!("channel is \"{}\"", channel));
+        }
+        if let Some(ref name) = self.name {
+            conditions.push(format!("name is \"{}\"", name));
+        }
+        if let Some(ref name) = self.name_contains {
+            conditions.push(format!("name contains \"{}\"", name));
+        }
+        if let Some(folder) = self.folder {
+            conditions.push(format!("in folder {}", folder));
+        }
+        if let Some(days) = self.older_than_days {
+            conditions.push(format!("older than {} days", days));
+        }
+        match self.watched {
+            Some(true) => conditions.push("watched".into()),
+            Some(false) => conditions.push("not watched".into()),
+            None => {}
+        }
+        if let Some(keep) = self.keep_newest {
+            try!(write!(fmt, "keep the {} newest of each programme where ", keep));
+        }
+        write!(fmt, "{}", conditions.join(" and "))
+    }
+}
+
+impl Decodable for Rule {
+    fn decode<D : Decoder>(d: &mut D) -> Result<Rule, D::Error> {
+        d.read_struct("Rule", 0, |d| {
+            let folder: Option<i32> = json_field!("folder", d);
+            Ok(Rule {
+                channel: json_field!("channel", d),
+                name: json_field!("name", d),
+                name_contains: json_field!("name_contains", d),
+                folder: folder.map(|id| if id == 0 { FolderId::Root } else { FolderId::FolderId(id) }),
+                older_than_days: json_field!("older_than_days", d),
+                watched: json_field!("watched", d),
+                keep_newest: json_field!("keep_newest", d),
+            })
+        })
+    }
+}
+
+/// Set of retention rules
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Retention {
+    pub rules: Vec<Rule>,
+}
+
+impl Retention {
+    /// Plans deleting the recordings selected by any of the rules at `now`
+    /// A recording selected by several rules is deleted once, for the first of them.
+    pub fn plan(&self, recordings: &[RecordingInfo], now: Timespec) -> Plan {
+        let mut plan = Plan::new();
+        let mut planned = HashSet::new();
+        for rule in self.rules.iter() {
+            let reason = rule.to_string();
+            for rinfo in rule.select(recordings, now) {
+                if planned.insert(rinfo.id) {
+                    plan.delete_recording(rinfo, &reason);
+                }
+            }
+        }
+        plan
+    }
+    /// Plans deleting the recordings of the tree under `root` selected by the rules now
+    /// The plan is only carried out when it is applied with `Plan::apply`.
+    pub fn plan_tree(&self, root: &Folder) -> Result<Plan, EVError> {
+        let walk = root.walk(WalkOptions {
+            on_error: ErrorPolicy::Abort,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            ..Default::default()
+        });
+        let recordings: Vec<RecordingInfo> = try!(walk.recordings().collect());
+        Ok(self.plan(&recordings, time::get_time()))
+    }
+}
+
+impl Decodable for Retention {
+    fn decode<D : Decoder>(d: &mut D) -> Result<Retention, D::Error> {
+        d.read_struct("Retention", 0, |d| {
+            Ok(Retention { rules: json_field!("rules", d) })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Retention, Rule};
+    use plan::{Operation, Outcome};
+    use rustc_serialize::json;
+    use session::Session;
+    use std::default::Default;
+    use std::sync::Arc;
+    use time::Timespec;
+    use transport::FixtureTransport;
+    use types::{ProgramId, RecordingInfo};
+    use urls::EVUrl;
+
+    fn recording(id: i32, name: &str, channel: &str, timestamp: &str, viewcount: i32) -> RecordingInfo {
+        let mut rinfo = RecordingInfo::default();
+        rinfo.id = id;
+        rinfo.name = name.into();
+        rinfo.channel = channel.into();
+        rinfo.timestamp = timestamp.into();
+        rinfo.viewcount = viewcount;
+        rinfo
+    }
+
+    fn recordings() -> Vec<RecordingInfo> {
+        vec![
+            recording(1, "Uutiset", "Yle TV1", "2014-03-01T20:30:00+0200", 1),
+            recording(2, "Uutiset", "Yle TV1", "2014-03-02T20:30:00+0200", 0),
+            recording(3, "Uutiset", "Yle TV1", "2014-03-03T20:30:00+0200", 1),
+            recording(4, "Elokuva", "MTV3", "2014-01-01T21:00:00+0200", 1),
+            recording(5, "Elokuva", "MTV3", "2014-03-03T21:00:00+0200", 1),
+        ]
+    }
+
+    /// 2014-03-04T00:00:00Z
+    fn now() -> Timespec {
+        Timespec::new(1393891200, 0)
+    }
+
+    #[test]
+    fn selects_old_watched_recordings() {
+        let rule = Rule {
+            channel: Some("mtv3".into()),
+            older_than_days: Some(30),
+            watched: Some(true),
+            ..Default::default()
+        };
+        let recordings = recordings();
+        let selected: Vec<i32> = rule.select(&recordings, now()).iter().map(|r| r.id).collect();
+        assert!(selected == vec![4]);
+        assert!(Rule::default().select(&recordings, now()).is_empty());
+    }
+
+    #[test]
+    fn keeps_newest_episodes() {
+        let retention: Retention = json::decode(
+            "{\"rules\":[{\"name\":\"uutiset\",\"keep_newest\":1},{\"watched\":true,\"name_contains\":\"uuti\"}]}"
+        ).unwrap();
+        let plan = retention.plan(&recordings(), now());
+        let deleted: Vec<i32> = plan.operations.iter().map(|op| match *op {
+            Operation::Delete { recording_id, .. } => recording_id,
+            _ => 0
+        }).collect();
+        assert!(deleted == vec![1, 2, 3]);
+        assert!(plan.to_string().starts_with("delete \"Uutiset\" (1) from folder 0: keep the 1 newest"));
+    }
+
+    #[test]
+    fn applies_deletions() {
+        let deletes = vec![EVUrl::Delete(ProgramId::ProgramId(123456789)), EVUrl::Delete(ProgramId::ProgramId(123456790))];
+        let transport = Arc::new(FixtureTransport::from_dir("testdata").unwrap());
+        for url in deletes.iter() {
+            transport.insert(url.clone(), "TRUE");
+        }
+        let root = Session::with_transport(transport.clone()).root().unwrap();
+        let rule = Rule { channel: Some("Yle TV2".into()), ..Default::default() };
+        let plan = Retention { rules: vec![rule] }.plan_tree(&root).unwrap();
+        assert!(plan.operations.len() == 2);
+        let encoded = json::encode(&plan).unwrap();
+        assert!(json::decode::<::plan::Plan>(&encoded).unwrap() == plan);
+        let report = plan.apply(&root).unwrap();
+        assert!(report.results.iter().all(|&(_, ref outcome)| match *outcome { Outcome::Applied => true, _ => false }));
+        let sent: Vec<EVUrl> = transport.requests().into_iter().map(|r| r.url).filter(|url| deletes.contains(url)).collect();
+        assert!(sent == deletes);
+    }
+}
@@ -0,0 +1,515 @@
+/* vim: set et: */
+
+use cookie::Cookie as CookiePair;
+use cookie::CookieJar;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use rustc_serialize::{json, Decodable, Decoder};
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use time;
+use transport::{HyperTransport, Request, Transport};
+use types::{EVError, Folder, FolderId};
+use url::form_urlencoded::serialize;
+use urls::EVUrl;
+
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// Called after the session has logged in again
+type ReauthenticateHook = Arc<dyn Fn(&Session) + Send + Sync>;
+
+struct Inner {
+    transport: Box<dyn Transport>,
+    cookies: RwLock<Vec<CookiePair>>,
+    credentials: RwLock<Option<Credentials>>,
+    /// Incremented every time the session logs in again
+    generation: Mutex<usize>,
+    on_reauthenticate: RwLock<Option<ReauthenticateHook>>,
+}
+
+/// Session to Elisa Viihde
+/// Cloning a session is cheap, the clones share the same cookies and `Transport`.
+///
+/// A session that was logged in with `Session::login` stores the credentials and
+/// logs in again when Elisa Viihde no longer accepts the session cookie.
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<Inner>,
+}
+
+impl Default for Session {
+    fn default() -> Session {
+        Session::new()
+    }
+}
+
+impl Session {
+    /// Creates a session without any cookies
+    /// You probably want to use `authentication::login` instead of this function.
+    pub fn new() -> Session {
+        Session::with_cookies(Vec::new())
+    }
+    /// Creates a session that sends its requests to `base_url` instead of Elisa Viihde
+    pub fn with_base_url(base_url: &str) -> Session {
+        Session::with_transport(HyperTransport::with_base_url(base_url))
+    }
+    /// Creates a session that sends its requests through `transport`
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Session {
+        Session::from_parts(Box::new(transport), Vec::new())
+    }
+    /// Creates a session from cookies that contain a valid session token
+    pub fn with_cookies(cookies: Vec<CookiePair>) -> Session {
+        Session::from_parts(Box::new(HyperTransport::new()), cookies)
+    }
+    fn from_parts(transport: Box<dyn Transport>, cookies: Vec<CookiePair>) -> Session {
+        Session {
+            inner: Arc::new(Inner {
+                transport: transport,
+                cookies: RwLock::new(cookies),
+                credentials: RwLock::new(None),
+                generation: Mutex::new(0),
+                on_reauthenticate: RwLock::new(None),
+            })
+        }
+    }
+    /// Creates a session from the cookies of `jar`
+    pub fn from_cookie_jar(jar: &CookieJar) -> Session {
+        Session::with_cookies(jar.iter().collect())
+    }
+    /// Logs in to Elisa Viihde
+    /// The credentials are kept in memory for logging in again when the session expires.
+    pub fn login(&self, username: &str, password: &str) -> Result<(), EVError> {
+        try!(self.authenticate(username, password));
+        self.set_credentials(username, password);
+        Ok(())
+    }
+    /// Saves the cookies of this session to `path`
+    /// The credentials are not saved.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), EVError> {
+        let saved = SavedSession::new(self.cookies());
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        restrict_permissions(&mut options);
+        let mut file = try!(options.open(path));
+        try!(file.write_all(saved.to_json().to_string().as_bytes()));
+        Ok(())
+    }
+    /// Loads a session saved with `Session::save` from `path`
+    /// The session is validated by requesting the root folder. Returns an `Authentication`
+    /// error if the session has expired or Elisa Viihde does not accept it anymore.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Session, EVError> {
+        Session::load_with(HyperTransport::new(), path)
+    }
+    /// Loads a session saved with `Session::save` from `path` that sends its requests through `transport`
+    pub fn load_with<T: Transport + 'static, P: AsRef<Path>>(transport: T, path: P) -> Result<Session, EVError> {
+        let session = Session::with_transport(transport);
+        try!(session.restore(path));
+        Ok(session)
+    }
+    /// Loads a session from `path` or logs in if the saved session can not be used
+    /// A session that was logged in is saved to `path`.
+    pub fn load_or_login<P: AsRef<Path>>(path: P, username: &str, password: &str) -> Result<Session, EVError> {
+        Session::load_or_login_with(HyperTransport::new(), path, username, password)
+    }
+    /// Loads a session from `path` or logs in through `transport`
+    /// See `Session::load_or_login`.
+    pub fn load_or_login_with<T, P>(transport: T, path: P, username: &str, password: &str) -> Result<Session, EVError>
+        where T: Transport + 'static, P: AsRef<Path> {
+        let session = Session::with_transport(transport);
+        try!(session.restore_or_login(path, username, password));
+        Ok(session)
+    }
+    /// Replaces the cookies of this session with the ones saved to `path`
+    /// The cookies of this session are kept if the saved session can not be used.
+    /// See `Session::load`.
+    pub fn restore<P: AsRef<Path>>(&self, path: P) -> Result<(), EVError> {
+        let saved = try!(SavedSession::read(path));
+        if saved.is_expired(time::get_time().sec) {
+            return Err(EVError::Authentication("The saved session has expired".into()));
+        }
+        let previous = mem::replace(&mut *self.inner.cookies.write().unwrap(), saved.cookies);
+        let validated = self.validate();
+        if validated.is_err() {
+            *self.inner.cookies.write().unwrap() = previous;
+        }
+        validated
+    }
+    /// Restores the cookies saved to `path` or logs in if the saved session can not be used
+    /// Failing to reach Elisa Viihde while validating the saved session is returned
+    /// instead of logging in. See `Session::load_or_login`.
+    pub fn restore_or_login<P: AsRef<Path>>(&self, path: P, username: &str, password: &str) -> Result<(), EVError> {
+        match self.restore(&path) {
+            Ok(()) => {
+                self.set_credentials(username, password);
+                Ok(())
+            },
+            Err(e @ EVError::Fetch(..)) => Err(e),
+            Err(_) => {
+                try!(self.login(username, password));
+                self.save(&path)
+            }
+        }
+    }
+    /// Sets a function that is called every time the session has logged in again
+    pub fn on_reauthenticate<F>(&self, hook: F) where F: Fn(&Session) + Send + Sync + 'static {
+        *self.inner.on_reauthenticate.write().unwrap() = Some(Arc::new(hook));
+    }
+    /// Returns the cookies of this session
+    pub fn cookies(&self) -> Vec<CookiePair> {
+        self.inner.cookies.read().unwrap().clone()
+    }
+    /// Fetches the root folder from Elisa Viihde
+    pub fn root(&self) -> Result<Folder, EVError> {
+        Folder::fetch_root(self)
+    }
+    /// Sends a GET request to `url` and returns the body of the response
+    pub fn get(&self, url: EVUrl) -> Result<String, EVError> {
+        self.send(url, None)
+    }
+    /// Sends a POST request with a form encoded `body` to `url` and returns the body of the response
+    pub fn post(&self, url: EVUrl, body: &str) -> Result<String, EVError> {
+        self.send(url, Some(body))
+    }
+    fn set_credentials(&self, username: &str, password: &str) {
+        *self.inner.credentials.write().unwrap() = Some(Credentials {
+            username: username.into(),
+            password: password.into(),
+        });
+    }
+    fn authenticate(&self, username: &str, password: &str) -> Result<(), EVError> {
+        let data = serialize(&[
+                       ("username", username),
+                       ("password", password),
+                       ("ajax", "true")
+                   ]);
+        self.inner.cookies.write().unwrap().clear();
+        let (_, ok) = try!(self.send_once(EVUrl::Login, Some(&data)));
+        match ok.trim() {
+            "TRUE" => {
+                if self.inner.cookies.read().unwrap().is_empty() {
+                    Err(EVError::MissingSessionCookie)
+                }
+                else {
+                    Ok(())
+                }
+            },
+            _ => {
+                Err(EVError::Authentication("Invalid username or password".into()))
+            }
+        }
+    }
+    /// Checks that Elisa Viihde accepts the cookies of this session with a single request
+    fn validate(&self) -> Result<(), EVError> {
+        let url = EVUrl::Folder(FolderId::Root);
+        let (status, body) = try!(self.send_once(url.clone(), None));
+        if is_unauthenticated(status, &body) {
+            Err(EVError::Authentication("The saved session is not valid anymore".into()))
+        }
+        else if !status.is_success() {
+            Err(EVError::fetch(url, EVError::Status(status)))
+        }
+        else {
+            Ok(())
+        }
+    }
+    /// Logs in again unless another thread already did it after `generation`
+    /// Returns false if the session has no credentials to log in with.
+    fn reauthenticate(&self, generation: usize) -> Result<bool, EVError> {
+        let mut current = self.inner.generation.lock().unwrap();
+        if *current != generation {
+            return Ok(true);
+        }
+        {
+            let credentials = self.inner.credentials.read().unwrap();
+            match *credentials {
+                Some(ref c) => try!(self.authenticate(&c.username, &c.password)),
+                None => return Ok(false)
+            }
+        }
+        *current += 1;
+        drop(current);
+        // The hook is called without holding the lock, so it may set a new hook
+        let hook = self.inner.on_reauthenticate.read().unwrap().clone();
+        if let Some(hook) = hook {
+            hook(self);
+        }
+        Ok(true)
+    }
+    fn send(&self, url: EVUrl, body: Option<&str>) -> Result<String, EVError> {
+        let generation = *self.inner.generation.lock().unwrap();
+        let (mut status, mut ok) = try!(self.send_once(url.clone(), body));
+        if url != EVUrl::Login && is_unauthenticated(status, &ok) && try!(self.reauthenticate(generation)) {
+            let (retry_status, retry_ok) = try!(self.send_once(url.clone(), body));
+            status = retry_status;
+            ok = retry_ok;
+        }
+        if status.is_success() {
+            Ok(ok)
+        }
+        else {
+            Err(EVError::fetch(url, EVError::Status(status)))
+        }
+    }
+    fn send_once(&self, url: EVUrl, body: Option<&str>) -> Result<(StatusCode, String), EVError> {
+        let req = Request {
+            method: if body.is_some() { Method::Post } else { Method::Get },
+            url: url,
+            body: body.map(|b| b.to_owned()),
+            cookies: self.cookies(),
+        };
+        let res = try!(self.inner.transport.send(&req).map_err(|e| EVError::fetch(req.url.clone(), e)));
+        self.apply_cookies(&res.set_cookies);
+        Ok((res.status, res.body))
+    }
+    fn apply_cookies(&self, set_cookies: &[CookiePair]) {
+        let mut cookies = self.inner.cookies.write().unwrap();
+        for set_cookie in set_cookies {
+            cookies.retain(|c| c.name != set_cookie.name);
+            cookies.push(set_cookie.clone());
+        }
+    }
+}
+
+/// Elisa Viihde either refuses the request or redirects to its login page
+/// when the session cookie has expired.
+fn is_unauthenticated(status: StatusCode, body: &str) -> bool {
+    match status {
+        StatusCode::Unauthorized | StatusCode::Forbidden => true,
+        _ => body.trim_start().starts_with('<')
+    }
+}
+
+/// Cookies of a session as they are stored on disk
+struct SavedSession {
+    /// Seconds since the epoch when the session was saved
+    saved_at: i64,
+    /// Seconds since the epoch when the first cookie expires
+    expires: Option<i64>,
+    cookies: Vec<CookiePair>,
+}
+
+impl SavedSession {
+    fn new(cookies: Vec<CookiePair>) -> SavedSession {
+        let now = time::get_time().sec;
+        let expires = cookies.iter()
+            .filter_map(|c| {
+                match (c.expires, c.max_age) {
+                    (_, Some(max_age)) => Some(now + max_age as i64),
+                    (Some(expires), None) => Some(expires.to_timespec().sec),
+                    (None, None) => None
+                }
+            })
+            .min();
+        SavedSession { saved_at: now, expires: expires, cookies: cookies }
+    }
+    fn read<P: AsRef<Path>>(path: P) -> Result<SavedSession, EVError> {
+        let mut s = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut s));
+        json::decode(&s).map_err(EVError::from)
+    }
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires.map(|expires| expires <= now).unwrap_or(false)
+    }
+}
+
+impl ToJson for SavedSession {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("saved_at".to_string(), self.saved_at.to_json());
+        obj.insert("expires".to_string(), self.expires.to_json());
+        obj.insert("cookies".to_string(), self.cookies.iter().map(|c| c.to_string()).collect::<Vec<_>>().to_json());
+        Json::Object(obj)
+    }
+}
+
+impl Decodable for SavedSession {
+    fn decode<D : Decoder>(d: &mut D) -> Result<SavedSession, D::Error> {
+        d.read_struct("", 0, |d| {
+            Ok(SavedSession {
+                saved_at: json_field!("saved_at", d),
+                expires: json_field!("expires", d),
+                cookies: {
+                    let cookies: Vec<String> = json_field!("cookies", d);
+                    let mut parsed = Vec::with_capacity(cookies.len());
+                    for c in cookies.iter() {
+                        parsed.push(try!(CookiePair::parse(c).map_err(|_| d.error("Invalid cookie"))));
+                    }
+                    parsed
+                },
+            })
+        })
+    }
+}
+
+/// Only the owner should be able to read the session token
+#[cfg(unix)]
+fn restrict_permissions(options: &mut OpenOptions) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+}
+#[cfg(not(unix))]
+fn restrict_permissions(_: &mut OpenOptions) {}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Session {{ cookies: {} }}", self.inner.cookies.read().unwrap().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_unauthenticated, SavedSession, Session};
+    use cookie::Cookie as CookiePair;
+    use hyper::status::StatusCode;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use transport::{FixtureTransport, Request, Response, Transport};
+    use types::{EVError, FolderId};
+    use urls::EVUrl;
+
+    /// Serves the login page until the client has logged in
+    struct ExpiringTransport {
+        fixtures: FixtureTransport,
+    }
+
+    impl Transport for ExpiringTransport {
+        fn send(&self, request: &Request) -> Result<Response, EVError> {
+            let logged_in = request.cookies.iter().any(|c| c.name == "JSESSIONID");
+            if request.url == EVUrl::Login || logged_in {
+                self.fixtures.send(request)
+            }
+            else {
+                Ok(Response::ok("<html><body>Kirjaudu sisään</body></html>"))
+            }
+        }
+    }
+
+    /// Fails every request as if Elisa Viihde could not be reached
+    struct OfflineTransport {
+        requests: AtomicUsize,
+    }
+
+    impl Transport for OfflineTransport {
+        fn send(&self, _: &Request) -> Result<Response, EVError> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            Err(EVError::IO(io::Error::new(io::ErrorKind::ConnectionRefused, "offline")))
+        }
+    }
+
+    #[test]
+    fn detects_expired_sessions() {
+        assert!(is_unauthenticated(StatusCode::Forbidden, ""));
+        assert!(is_unauthenticated(StatusCode::Ok, "\n<html><body>Kirjaudu</body></html>"));
+        assert!(!is_unauthenticated(StatusCode::Ok, "{\"ready_data\":[]}"));
+        assert!(!is_unauthenticated(StatusCode::Ok, "TRUE"));
+    }
+
+    #[test]
+    fn saved_session_keeps_cookies_and_expiry() {
+        let mut cookie = CookiePair::new("JSESSIONID".into(), "abc123".into());
+        cookie.max_age = Some(600);
+        let session = Session::with_cookies(vec![cookie]);
+        let path = env::temp_dir().join(format!("evimproved-saved-session-{}.json", ::std::process::id()));
+        session.save(&path).unwrap();
+        let saved = SavedSession::read(&path).unwrap();
+        assert!(saved.cookies.len() == 1);
+        assert!(saved.cookies[0].name == "JSESSIONID" && saved.cookies[0].value == "abc123");
+        assert!(saved.expires == Some(saved.saved_at + 600));
+        assert!(!saved.is_expired(saved.saved_at));
+        assert!(saved.is_expired(saved.saved_at + 600));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn logs_in_again_when_session_expires() {
+        let fixtures = FixtureTransport::from_dir("testdata").unwrap();
+        let session = Session::with_transport(ExpiringTransport { fixtures: fixtures });
+        session.login("username", "password").unwrap();
+        let relogins = Arc::new(AtomicUsize::new(0));
+        let counter = relogins.clone();
+        session.on_reauthenticate(move |s| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let counter = counter.clone();
+            s.on_reauthenticate(move |_| { counter.fetch_add(1, Ordering::SeqCst); });
+        });
+        session.inner.cookies.write().unwrap().clear();
+        assert!(session.get(EVUrl::Folder(FolderId::Root)).unwrap().starts_with("{"));
+        assert!(relogins.load(Ordering::SeqCst) == 1);
+    }
+
+    #[test]
+    fn restores_saved_session() {
+        let path = env::temp_dir().join(format!("evimproved-restored-session-{}.json", ::std::process::id()));
+        let session = Session::with_transport(FixtureTransport::from_dir("testdata").unwrap());
+        session.login("username", "password").unwrap();
+        session.save(&path).unwrap();
+        let restored = Session::load_with(FixtureTransport::from_dir("testdata").unwrap(), &path).unwrap();
+        assert!(restored.cookies().len() == 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn logs_in_without_a_saved_session() {
+        let path = env::temp_dir().join(format!("evimproved-new-session-{}.json", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let transport = Arc::new(FixtureTransport::from_dir("testdata").unwrap());
+        let session = Session::load_or_login_with(transport.clone(), &path, "username", "password").unwrap();
+        assert!(transport.requests()[0].url == EVUrl::Login);
+        assert!(SavedSession::read(&path).unwrap().cookies == session.cookies());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejected_session_keeps_the_cookies() {
+        let path = env::temp_dir().join(format!("evimproved-rejected-session-{}.json", ::std::process::id()));
+        Session::with_cookies(vec![CookiePair::new("EXPIRED".into(), "abc123".into())]).save(&path).unwrap();
+        let fixtures = FixtureTransport::from_dir("testdata").unwrap();
+        let session = Session::with_transport(ExpiringTransport { fixtures: fixtures });
+        session.login("username", "password").unwrap();
+        let cookies = session.cookies();
+        match session.restore(&path) {
+            Err(EVError::Authentication(_)) => {},
+            _ => assert!(false, "A session that is not logged in should not be restored")
+        }
+        assert!(session.cookies() == cookies);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unreachable_server_does_not_log_in() {
+        let path = env::temp_dir().join(format!("evimproved-offline-session-{}.json", ::std::process::id()));
+        Session::with_cookies(vec![CookiePair::new("JSESSIONID".into(), "abc123".into())]).save(&path).unwrap();
+        let transport = Arc::new(OfflineTransport { requests: AtomicUsize::new(0) });
+        let session = Session::with_transport(transport.clone());
+        match session.restore_or_login(&path, "username", "password") {
+            Err(EVError::Fetch(EVUrl::Folder(FolderId::Root), _)) => {},
+            _ => assert!(false, "Failing to validate the session should be returned")
+        }
+        assert!(transport.requests.load(Ordering::SeqCst) == 1);
+        assert!(session.cookies().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn login_without_cookie_fails() {
+        let transport = FixtureTransport::new();
+        transport.insert(EVUrl::Login, "TRUE");
+        let session = Session::with_transport(transport);
+        match session.login("username", "password") {
+            Err(EVError::MissingSessionCookie) => {},
+            _ => assert!(false, "Login without a session cookie should fail")
+        }
+    }
+}
@@ -0,0 +1,281 @@
+/* vim: set et: */
+
+use rustc_serialize::{Decodable, Decoder};
+use std::cmp::Ordering;
+use std::fmt;
+use time::{self, Timespec, Tm};
+
+/// Abbreviations of the weekdays in Finnish, starting from Monday
+const WEEKDAYS: [&'static str; 7] = ["ma", "ti", "ke", "to", "pe", "la", "su"];
+
+const HOUR: i64 = 3600;
+/// Offset of Finnish standard time from UTC
+const EET: i64 = 2 * HOUR;
+/// Offset of Finnish daylight saving time from UTC
+const EEST: i64 = 3 * HOUR;
+
+/// Point in time reported by Elisa Viihde
+///
+/// Elisa Viihde uses several formats for dates:
+///
+/// * `ma 17.03.2014 00:05` with a Finnish weekday abbreviation
+/// * `2014-03-17T00:05:00+0200`
+/// * `18.9.2013 12:35:00`
+///
+/// Times without an offset are in Helsinki time. The string Elisa Viihde sent is kept,
+/// so a timestamp in an unknown format is not lost, but it sorts before all parsed ones.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Timestamp {
+    raw: String,
+    time: Option<Timespec>,
+}
+
+impl Timestamp {
+    /// Parses `raw` in any of the formats used by Elisa Viihde
+    pub fn new(raw: &str) -> Timestamp {
+        Timestamp {
+            raw: raw.into(),
+            time: parse(raw.trim()).map(|sec| Timespec::new(sec, 0)),
+        }
+    }
+    /// Returns the string Elisa Viihde sent
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+    /// Returns the point in time, or `None` if the format was not recognised
+    pub fn timespec(&self) -> Option<Timespec> {
+        self.time
+    }
+    /// Returns the time in UTC
+    pub fn utc(&self) -> Option<Tm> {
+        self.time.map(time::at_utc)
+    }
+    /// Returns the time in Helsinki, with `tm_utcoff` set to the offset in effect
+    pub fn helsinki(&self) -> Option<Tm> {
+        self.time.map(|t| {
+            let offset = helsinki_offset(t.sec);
+            let mut tm = time::at_utc(Timespec::new(t.sec + offset, 0));
+            tm.tm_utcoff = offset as i32;
+            tm
+        })
+    }
+    /// Returns true if this timestamp is at or after `from` and before `to`
+    pub fn is_between(&self, from: Timespec, to: Timespec) -> bool {
+        self.time.map_or(false, |t| from <= t && t < to)
+    }
+}
+
+impl<'a> From<&'a str> for Timestamp {
+    fn from(raw: &'a str) -> Timestamp {
+        Timestamp::new(raw)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.raw)
+    }
+}
+
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Timestamp) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Timestamp) -> Ordering {
+        self.time.cmp(&other.time).then_with(|| self.raw.cmp(&other.raw))
+    }
+}
+
+impl Decodable for Timestamp {
+    fn decode<D : Decoder>(d: &mut D) -> Result<Timestamp, D::Error> {
+        let raw = try!(d.read_str());
+        Ok(Timestamp::new(&raw))
+    }
+}
+
+/// Returns the seconds since the epoch of `s`
+fn parse(s: &str) -> Option<i64> {
+    if s.contains('T') {
+        parse_iso(s)
+    }
+    else {
+        parse_finnish(s).map(helsinki_to_utc)
+    }
+}
+
+/// Parses `2014-03-17T00:05:00+0200`
+fn parse_iso(s: &str) -> Option<i64> {
+    let idx = s.find('T')?;
+    let (date, rest) = (&s[..idx], &s[idx + 1..]);
+    let (time, offset) = match rest.find(['+', '-', 'Z']) {
+        Some(idx) => match parse_offset(&rest[idx..]) {
+            Some(offset) => (&rest[..idx], Some(offset)),
+            None => return None
+        },
+        None => (rest, None)
+    };
+    let local = match (numbers(date, '-'), numbers(time, ':')) {
+        (Some(date), Some(time)) => match (&date[..], &time[..]) {
+            (&[y, m, d], &[h, min]) => local_seconds(y, m, d, h, min, 0),
+            (&[y, m, d], &[h, min, sec]) => local_seconds(y, m, d, h, min, sec),
+            _ => None
+        },
+        _ => None
+    };
+    match offset {
+        Some(offset) => local.map(|local| local - offset),
+        None => local.map(helsinki_to_utc)
+    }
+}
+
+/// Parses `+0200`, `+02:00` and `Z` to seconds east of UTC
+fn parse_offset(s: &str) -> Option<i64> {
+    if s == "Z" {
+        return Some(0);
+    }
+    let sign = if s.starts_with('-') { -1 } else { 1 };
+    let digits: String = s[1..].chars().filter(|&c| c != ':').collect();
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    match (digits[..2].parse::<i64>(), digits[2..].parse::<i64>()) {
+        (Ok(hours), Ok(minutes)) => Some(sign * (hours * HOUR + minutes * 60)),
+        _ => None
+    }
+}
+
+/// Parses `ma 17.03.2014 00:05` and `18.9.2013 12:35:00` to local seconds
+fn parse_finnish(s: &str) -> Option<i64> {
+    let mut words: Vec<&str> = s.split_whitespace().collect();
+    if words.len() == 3 {
+        if !WEEKDAYS.contains(&&words[0].to_lowercase()[..]) {
+            return None;
+        }
+        words.remove(0);
+    }
+    if words.len() != 2 {
+        return None;
+    }
+    match (numbers(words[0], '.'), numbers(words[1], ':')) {
+        (Some(date), Some(time)) => match (&date[..], &time[..]) {
+            (&[d, m, y], &[h, min]) => local_seconds(y, m, d, h, min, 0),
+            (&[d, m, y], &[h, min, sec]) => local_seconds(y, m, d, h, min, sec),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+/// Converts local seconds in Helsinki time to seconds since the epoch
+/// The hour repeated when daylight saving time ends is taken to be in daylight saving time.
+fn helsinki_to_utc(local: i64) -> i64 {
+    if helsinki_offset(local - EEST) == EEST {
+        local - EEST
+    }
+    else {
+        local - EET
+    }
+}
+
+fn numbers(s: &str, separator: char) -> Option<Vec<i64>> {
+    s.split(separator).map(|n| n.parse().ok()).collect()
+}
+
+/// Returns the seconds since the epoch of a date and time as if it was in UTC,
+/// or `None` if it is not a valid date and time
+fn local_seconds(year: i64, month: i64, day: i64, hour: i64, minute: i64, second: i64) -> Option<i64> {
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) ||
+        !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..60).contains(&second) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 24 * HOUR + hour * HOUR + minute * 60 + second)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+/// Returns the number of days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Returns the seconds since the epoch of 01:00 UTC on the last Sunday of `month`,
+/// when daylight saving time starts and ends in the EU
+fn last_sunday_at_one(year: i64, month: i64) -> i64 {
+    let last = days_from_civil(year, month, days_in_month(year, month));
+    // Days since Monday, 1970-01-01 being a Thursday
+    let weekday = (last + 3).rem_euclid(7);
+    (last - (weekday + 1) % 7) * 24 * HOUR + HOUR
+}
+
+/// Returns the offset of Helsinki time from UTC at `utc` seconds since the epoch
+fn helsinki_offset(utc: i64) -> i64 {
+    let year = time::at_utc(Timespec::new(utc, 0)).tm_year as i64 + 1900;
+    if last_sunday_at_one(year, 3) <= utc && utc < last_sunday_at_one(year, 10) {
+        EEST
+    }
+    else {
+        EET
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timestamp;
+
+    #[test]
+    fn parses_all_formats() {
+        let weekday = Timestamp::new("ma 17.03.2014 00:05");
+        let iso = Timestamp::new("2014-03-17T00:05:00+0200");
+        assert!(weekday.timespec().is_some());
+        assert!(weekday.timespec() == iso.timespec());
+        assert!(weekday.raw() == "ma 17.03.2014 00:05");
+        let summer = Timestamp::new("18.9.2013 12:35:00");
+        assert!(summer.timespec() == Timestamp::new("2013-09-18T12:35:00+0300").timespec());
+        let utc = summer.utc().unwrap();
+        assert!(utc.tm_hour == 9 && utc.tm_min == 35);
+        let helsinki = summer.helsinki().unwrap();
+        assert!(helsinki.tm_hour == 12 && helsinki.tm_utcoff == 3 * 3600);
+    }
+
+    #[test]
+    fn handles_daylight_saving_time_changes() {
+        // Clocks were turned forward at 03:00 on 2014-03-30 and back at 04:00 on 2014-10-26
+        assert!(Timestamp::new("30.3.2014 02:59").timespec() == Timestamp::new("2014-03-30T00:59:00Z").timespec());
+        assert!(Timestamp::new("30.3.2014 04:00").timespec() == Timestamp::new("2014-03-30T01:00:00Z").timespec());
+        assert!(Timestamp::new("26.10.2014 03:30").timespec() == Timestamp::new("2014-10-26T00:30:00Z").timespec());
+        assert!(Timestamp::new("26.10.2014 04:00").timespec() == Timestamp::new("2014-10-26T02:00:00Z").timespec());
+    }
+
+    #[test]
+    fn sorts_chronologically() {
+        let mut timestamps = [
+            Timestamp::new("2014-04-17T00:05:00+0200"),
+            Timestamp::new("not a date"),
+            Timestamp::new("ma 17.03.2014 00:05"),
+            Timestamp::new("xx 17.03.2014 00:05"),
+        ];
+        timestamps.sort();
+        assert!(timestamps[2].raw() == "ma 17.03.2014 00:05");
+        assert!(timestamps[3].raw() == "2014-04-17T00:05:00+0200");
+        assert!(timestamps[0].timespec().is_none() && timestamps[1].timespec().is_none());
+        let from = timestamps[2].timespec().unwrap();
+        let to = timestamps[3].timespec().unwrap();
+        assert!(timestamps[2].is_between(from, to));
+        assert!(!timestamps[3].is_between(from, to));
+    }
+}
@@ -0,0 +1,228 @@
+/* vim: set et: */
+
+use cookie::Cookie as CookiePair;
+use headers::evimproved_headers;
+use hyper::client::Client;
+use hyper::header::{Cookie, SetCookie};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use types::{EVError, FolderId, ProgramId};
+use urls::{EVUrl, DEFAULT_BASE_URL};
+
+const EMPTY_PAGE: &'static str = "{\"ready_data\":[{\"folders\":[],\"recordings\":[]}]}";
+
+/// Request to an Elisa Viihde endpoint
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: Method,
+    pub url: EVUrl,
+    /// Form encoded body of a POST request
+    pub body: Option<String>,
+    pub cookies: Vec<CookiePair>,
+}
+
+/// Response from an Elisa Viihde endpoint
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub set_cookies: Vec<CookiePair>,
+    pub body: String,
+}
+
+impl Response {
+    /// Creates a successful response with `body`
+    pub fn ok(body: &str) -> Response {
+        Response {
+            status: StatusCode::Ok,
+            set_cookies: Vec::new(),
+            body: body.into(),
+        }
+    }
+}
+
+/// Sends requests to Elisa Viihde on behalf of a `Session`
+pub trait Transport: Send + Sync {
+    fn send(&self, request: &Request) -> Result<Response, EVError>;
+}
+
+/// Shares a transport, for example to inspect the requests a `FixtureTransport` received
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn send(&self, request: &Request) -> Result<Response, EVError> {
+        (**self).send(request)
+    }
+}
+
+/// Transport that sends the requests over HTTP
+pub struct HyperTransport {
+    client: Client,
+    base_url: String,
+}
+
+impl Default for HyperTransport {
+    fn default() -> HyperTransport {
+        HyperTransport::new()
+    }
+}
+
+impl HyperTransport {
+    /// Creates a transport that sends the requests to Elisa Viihde
+    pub fn new() -> HyperTransport {
+        HyperTransport::with_base_url(DEFAULT_BASE_URL)
+    }
+    /// Creates a transport that sends the requests to `base_url` instead of Elisa Viihde,
+    /// for example to a local mock server or a proxy
+    pub fn with_base_url(base_url: &str) -> HyperTransport {
+        HyperTransport {
+            client: Client::new(),
+            base_url: base_url.into(),
+        }
+    }
+    /// Returns the url the requests are sent to
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+}
+
+impl Transport for HyperTransport {
+    fn send(&self, request: &Request) -> Result<Response, EVError> {
+        let url = request.url.with_base(&self.base_url);
+        let req = match request.body {
+            Some(ref body) => self.client.request(request.method.clone(), &url[..]).body(&body[..]),
+            None => self.client.request(request.method.clone(), &url[..])
+        };
+        let mut headers = evimproved_headers(None);
+        if !request.cookies.is_empty() {
+            headers.set(Cookie(request.cookies.clone()));
+        }
+        let mut res = try!(req.headers(headers).send());
+        let mut body = String::new();
+        try!(res.read_to_string(&mut body));
+        Ok(Response {
+            status: res.status,
+            set_cookies: match res.headers.get::<SetCookie>() {
+                Some(&SetCookie(ref cookies)) => cookies.clone(),
+                None => Vec::new()
+            },
+            body: body,
+        })
+    }
+}
+
+/// Transport that serves canned responses without a network connection
+/// Requests to urls without a response are answered with `404 Not Found`.
+pub struct FixtureTransport {
+    responses: RwLock<HashMap<EVUrl, Response>>,
+    requests: Mutex<Vec<Request>>,
+}
+
+impl Default for FixtureTransport {
+    fn default() -> FixtureTransport {
+        FixtureTransport::new()
+    }
+}
+
+impl FixtureTransport {
+    /// Creates a transport without any responses
+    pub fn new() -> FixtureTransport {
+        FixtureTransport {
+            responses: RwLock::new(HashMap::new()),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+    /// Creates a transport that serves the files of `dir`
+    /// The first line of each file is served as the response body:
+    ///
+    /// * `root_folder.json` for the root folder
+    /// * `folder_<id>.json` for the folder with `id`
+    /// * `recording_<id>.json` for the program with `id`
+    ///
+    /// Each folder listing is a single page, the page after it is empty.
+    /// Logging in succeeds with any credentials.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<FixtureTransport, EVError> {
+        let transport = FixtureTransport::new();
+        let mut login = Response::ok("TRUE");
+        login.set_cookies.push(CookiePair::new("JSESSIONID".into(), "fixture".into()));
+        transport.insert_response(EVUrl::Login, login);
+        for entry in try!(fs::read_dir(dir)) {
+            let path = try!(entry).path();
+            let url = match path.file_stem().and_then(|s| s.to_str()) {
+                Some("root_folder") => EVUrl::Folder(FolderId::Root),
+                Some(stem) if stem.starts_with("folder_") => {
+                    match stem["folder_".len()..].parse() {
+                        Ok(id) => EVUrl::Folder(FolderId::FolderId(id)),
+                        Err(_) => continue
+                    }
+                },
+                Some(stem) if stem.starts_with("recording_") => {
+                    match stem["recording_".len()..].parse() {
+                        Ok(id) => EVUrl::Program(ProgramId::ProgramId(id)),
+                        Err(_) => continue
+                    }
+                },
+                _ => continue
+            };
+            if let EVUrl::Folder(id) = url {
+                transport.insert(EVUrl::FolderPage(id, 1), EMPTY_PAGE);
+            }
+            let mut line = String::new();
+            try!(BufReader::new(try!(File::open(&path))).read_line(&mut line));
+            transport.insert(url, line.trim_end());
+        }
+        Ok(transport)
+    }
+    /// Serves `body` for requests to `url`
+    pub fn insert(&self, url: EVUrl, body: &str) {
+        self.insert_response(url, Response::ok(body));
+    }
+    /// Serves `response` for requests to `url`
+    pub fn insert_response(&self, url: EVUrl, response: Response) {
+        self.responses.write().unwrap().insert(url, response);
+    }
+    /// Returns the requests sent through this transport
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Transport for FixtureTransport {
+    fn send(&self, request: &Request) -> Result<Response, EVError> {
+        self.requests.lock().unwrap().push(request.clone());
+        match self.responses.read().unwrap().get(&request.url) {
+            Some(response) => Ok(response.clone()),
+            None => Ok(Response {
+                status: StatusCode::NotFound,
+                set_cookies: Vec::new(),
+                body: String::new(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FixtureTransport, Request, Transport};
+    use hyper::method::Method;
+    use hyper::status::StatusCode;
+    use types::{FolderId, ProgramId};
+    use urls::EVUrl;
+
+    fn get(transport: &FixtureTransport, url: EVUrl) -> StatusCode {
+        let req = Request { method: Method::Get, url: url, body: None, cookies: Vec::new() };
+        transport.send(&req).unwrap().status
+    }
+
+    #[test]
+    fn serves_testdata() {
+        let transport = FixtureTransport::from_dir("testdata").unwrap();
+        assert!(get(&transport, EVUrl::Folder(FolderId::Root)) == StatusCode::Ok);
+        assert!(get(&transport, EVUrl::Folder(FolderId::FolderId(1000002))) == StatusCode::Ok);
+        assert!(get(&transport, EVUrl::Program(ProgramId::ProgramId(1000001))) == StatusCode::Ok);
+        assert!(get(&transport, EVUrl::Folder(FolderId::FolderId(1))) == StatusCode::NotFound);
+        assert!(transport.requests().len() == 4);
+    }
+}
@@ -1,34 +1,29 @@
 /* vim: set et: */
 
-use headers::evimproved_headers;
+use session::Session;
 use traits::Fetch;
 
-#[cfg(not(test))]
-use hyper::client::Client;
-use hyper::header::Headers;
+use std::collections::HashSet;
 use std::default::Default;
 use std::fmt;
-use std::io::Read;
+use std::mem;
 use url::Url;
 use url::percent_encoding::percent_decode;
-#[cfg(not(test))]
 use urls::EVUrl;
-use std::vec;
+use walk::{Walk, WalkOptions, WalkRecordings};
+use timestamp::Timestamp;
+use pool::{FetchPool, DEFAULT_MAX_IN_FLIGHT};
 use std::str::FromStr;
+use std::iter::Sum;
+use std::ops::Add;
 use std::error::Error;
-use cookie::CookieJar;
+use std::io;
+use hyper;
+use hyper::status::StatusCode;
 
-use std::iter::{Chain,Filter,FlatMap,Map};
 
-use std::sync::mpsc::{channel};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
-
-#[cfg(test)]
-use std::fs::File;
-#[cfg(test)]
-use std::io::BufRead;
+use std::sync::mpsc::RecvError;
+use time::Duration;
 
 #[allow(unused_imports)]
 use rustc_serialize::{json,Decodable,Decoder};
@@ -41,46 +36,176 @@ macro_rules! json_field {
 
 #[derive(Debug)]
 pub enum EVError {
+    /// Logging in failed or the session could not be restored
     Authentication(String),
-    Decoder(String),
-    IO(String),
-    Http(String),
-    Fetch,
-    NotFound
+    /// The response could not be decoded
+    Decoder(json::DecoderError),
+    /// Reading or writing a file or the network connection failed
+    IO(io::Error),
+    /// The HTTP request could not be sent or its response could not be read
+    Http(hyper::Error),
+    /// Elisa Viihde answered with an unsuccessful status
+    Status(StatusCode),
+    /// Elisa Viihde refused to carry out the request
+    Refused,
+    /// Requesting `EVUrl` failed because of the contained error
+    Fetch(EVUrl, Box<EVError>),
+    /// The requested folder or recording does not exist
+    NotFound,
+    /// The value is not bound to a `Session`
+    NoSession,
+    /// Elisa Viihde accepted the login but did not set a session cookie
+    MissingSessionCookie,
+    /// The response was valid JSON but not structured as expected
+    UnexpectedShape(String),
+    /// A field of the response was not valid percent-encoded UTF-8
+    InvalidEncoding(String),
+    /// The folder is protected with a PIN and deleting its recordings has not been allowed
+    Locked(FolderId),
+    /// The download ended with a different number of bytes than the server reported,
+    /// the expected size first
+    SizeMismatch(u64, u64),
+    /// A replayed cassette has no response to the request, described by its method and path
+    NotRecorded(String),
+    /// The thread fetching the folder panicked
+    WorkerPanicked,
+}
+impl EVError {
+    /// Wraps `e` with the url that was being requested when it occurred
+    pub fn fetch(url: EVUrl, e: EVError) -> EVError {
+        match e {
+            EVError::Fetch(..) => e,
+            e => EVError::Fetch(url, Box::new(e))
+        }
+    }
 }
 impl fmt::Display for EVError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "foo")
+        match *self {
+            EVError::Authentication(ref msg) => write!(fmt, "Authentication failed: {}", msg),
+            EVError::Decoder(ref e) => write!(fmt, "Invalid response: {}", e),
+            EVError::IO(ref e) => write!(fmt, "I/O error: {}", e),
+            EVError::Http(ref e) => write!(fmt, "HTTP error: {}", e),
+            EVError::Status(ref status) => write!(fmt, "Unexpected response status: {}", status),
+            EVError::Refused => write!(fmt, "The request was refused"),
+            EVError::Fetch(ref url, ref e) => {
+                match *url {
+                    EVUrl::Login => write!(fmt, "Logging in failed: {}", e),
+                    EVUrl::Folder(FolderId::Root) => write!(fmt, "Fetching the root folder failed: {}", e),
+                    EVUrl::Folder(ref id) => write!(fmt, "Fetching folder {} failed: {}", id, e),
+                    EVUrl::FolderPage(ref id, page) => write!(fmt, "Fetching page {} of folder {} failed: {}", page, id, e),
+                    EVUrl::Program(ref id) => write!(fmt, "Fetching program {} failed: {}", id, e),
+                    EVUrl::Move(ref pid, ref fid) => write!(fmt, "Moving recording {} to folder {} failed: {}", pid, fid, e),
+                    EVUrl::Delete(ref pid) => write!(fmt, "Deleting recording {} failed: {}", pid, e),
+                }
+            },
+            EVError::NotFound => write!(fmt, "Not found"),
+            EVError::NoSession => write!(fmt, "Not bound to a session"),
+            EVError::MissingSessionCookie => write!(fmt, "No session cookie in the login response"),
+            EVError::UnexpectedShape(ref msg) => write!(fmt, "Unexpected response: {}", msg),
+            EVError::InvalidEncoding(ref field) => write!(fmt, "Field {} is not valid UTF-8", field),
+            EVError::Locked(ref id) => write!(fmt, "Folder {} is protected with a PIN", id),
+            EVError::SizeMismatch(expected, actual) => write!(fmt, "Expected {} bytes but got {}", expected, actual),
+            EVError::NotRecorded(ref request) => write!(fmt, "No recorded response for {}", request),
+            EVError::WorkerPanicked => write!(fmt, "The fetching thread panicked"),
+        }
     }
 }
 impl Error for EVError {
     fn description(&self) -> &str {
-        "An error occurred"
+        match *self {
+            EVError::Authentication(_) => "Authentication failed",
+            EVError::Decoder(_) => "Invalid response",
+            EVError::IO(_) => "I/O error",
+            EVError::Http(_) => "HTTP error",
+            EVError::Status(_) => "Unexpected response status",
+            EVError::Refused => "The request was refused",
+            EVError::Fetch(..) => "Request failed",
+            EVError::NotFound => "Not found",
+            EVError::NoSession => "Not bound to a session",
+            EVError::MissingSessionCookie => "No session cookie in the login response",
+            EVError::UnexpectedShape(_) => "Unexpected response",
+            EVError::InvalidEncoding(_) => "Invalid encoding",
+            EVError::Locked(_) => "The folder is protected with a PIN",
+            EVError::SizeMismatch(..) => "Unexpected size",
+            EVError::NotRecorded(_) => "No recorded response",
+            EVError::WorkerPanicked => "The fetching thread panicked",
+        }
+    }
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            EVError::Decoder(ref e) => Some(e),
+            EVError::IO(ref e) => Some(e),
+            EVError::Http(ref e) => Some(e),
+            EVError::Fetch(_, ref e) => Some(&**e),
+            _ => None
+        }
+    }
+}
+impl From<hyper::Error> for EVError {
+    fn from(e: hyper::Error) -> EVError {
+        EVError::Http(e)
     }
 }
-impl From<::hyper::error::Error> for EVError {
-    fn from(e: ::hyper::error::Error) -> EVError {
-        EVError::Http(e.to_string())
+impl From<io::Error> for EVError {
+    fn from(e: io::Error) -> EVError {
+        EVError::IO(e)
     }
 }
-impl From<::std::io::Error> for EVError {
-    fn from(e: ::std::io::Error) -> EVError {
-        EVError::IO(e.to_string())
+impl From<json::DecoderError> for EVError {
+    /// Decoder errors raised by the field readers of this module become typed errors again
+    fn from(e: json::DecoderError) -> EVError {
+        if let json::DecoderError::ApplicationError(ref msg) = e {
+            if let Some(shape) = msg.strip_prefix(UNEXPECTED_SHAPE) {
+                return EVError::UnexpectedShape(shape.to_string());
+            }
+            if let Some(field) = msg.strip_prefix(INVALID_ENCODING) {
+                return EVError::InvalidEncoding(field.to_string());
+            }
+        }
+        EVError::Decoder(e)
     }
 }
-impl From<::rustc_serialize::json::DecoderError> for EVError {
-    fn from(e: ::rustc_serialize::json::DecoderError) -> EVError {
-        EVError::Decoder(e.to_string())
+impl From<json::ParserError> for EVError {
+    fn from(e: json::ParserError) -> EVError {
+        EVError::Decoder(json::DecoderError::ParseError(e))
     }
 }
-impl From<::std::sync::mpsc::RecvError> for EVError {
-    fn from(_: ::std::sync::mpsc::RecvError) -> EVError {
-        EVError::Fetch
+impl From<RecvError> for EVError {
+    fn from(e: RecvError) -> EVError {
+        EVError::IO(io::Error::new(io::ErrorKind::BrokenPipe, e))
+    }
+}
+
+// Prefixes of decoder error messages that are turned into typed `EVError`s
+// `Decoder::error` only takes a message, so the type has to travel in it
+const UNEXPECTED_SHAPE: &'static str = "Unexpected shape: ";
+const INVALID_ENCODING: &'static str = "Invalid encoding: ";
+
+/// Decodes the response to a request to `url`
+fn decode_response<T: Decodable>(url: EVUrl, body: &str) -> Result<T, EVError> {
+    json::decode(body).map_err(|e| EVError::fetch(url, EVError::from(e)))
+}
+
+/// Reads the field `name` that holds `true` or `false` as a string
+fn bool_field<D: Decoder>(name: &str, d: &mut D) -> Result<bool, D::Error> {
+    let value: String = json_field!(name, d);
+    match &value.trim().to_lowercase()[..] {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(d.error(&format!("{}{} is not a boolean", UNEXPECTED_SHAPE, name)))
     }
 }
 
+/// Reads the percent-encoded string field `name`
+fn percent_decoded_field<D: Decoder>(name: &str, d: &mut D) -> Result<String, D::Error> {
+    let percent_encoded_str: String = json_field!(name, d);
+    String::from_utf8(percent_decode(percent_encoded_str.as_bytes()))
+        .map_err(|_| d.error(&format!("{}{}", INVALID_ENCODING, name)))
+}
+
 /// Describes an id of an folder
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum FolderId {
     Root,
     FolderId(i32)
@@ -94,30 +219,78 @@ impl fmt::Display for FolderId {
     }
 }
 
-/// Describes a size of a folder
-#[derive(Clone, Debug)]
-pub enum FolderSize {
-    Bytes(f32),
-    Kilobytes(f32),
-    Megabytes(f32),
-    Gigabytes(f32),
+/// Units of `FolderSize`, each 1024 times the previous one
+const SIZE_UNITS: [&'static str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+/// Describes a size of a folder in bytes
+/// Elisa Viihde reports sizes rounded to two decimals of the largest unit,
+/// so parsed sizes are only as exact as the string they were parsed from.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FolderSize {
+    bytes: u64,
+}
+impl FolderSize {
+    /// Creates a size of `bytes` bytes
+    pub fn from_bytes(bytes: u64) -> FolderSize {
+        FolderSize { bytes: bytes }
+    }
+    /// Returns the size in bytes
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+    /// Returns the size in gigabytes of 1024 megabytes
+    pub fn gigabytes(&self) -> f64 {
+        self.bytes as f64 / (1u64 << 30) as f64
+    }
+}
+impl Add for FolderSize {
+    type Output = FolderSize;
+    fn add(self, other: FolderSize) -> FolderSize {
+        FolderSize::from_bytes(self.bytes.saturating_add(other.bytes))
+    }
+}
+impl Sum for FolderSize {
+    fn sum<I: Iterator<Item=FolderSize>>(iter: I) -> FolderSize {
+        iter.fold(FolderSize::default(), Add::add)
+    }
+}
+impl<'a> Sum<&'a FolderSize> for FolderSize {
+    fn sum<I: Iterator<Item=&'a FolderSize>>(iter: I) -> FolderSize {
+        iter.cloned().sum()
+    }
+}
+impl fmt::Display for FolderSize {
+    /// Formats the size in the largest unit it has at least one of, like Elisa Viihde does
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut value = self.bytes as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < SIZE_UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(fmt, "{} B", self.bytes)
+        }
+        else {
+            write!(fmt, "{:.2} {}", value, SIZE_UNITS[unit])
+        }
+    }
 }
 impl FromStr for FolderSize {
     type Err = String;
+    /// Parses sizes such as `57.31 GB`, `57,31 GB`, `57.31GB` and `1.2 TB`
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut words = s.split_whitespace();
-        words.next()
-            .and_then(|val_str| str::parse::<f32>(val_str).map(|v| (v * 100.0).round() / 100.0).ok())
-            .and_then(|val| {
-                match words.next() {
-                    Some("GB") => Some(FolderSize::Gigabytes(val)),
-                    Some("MB") => Some(FolderSize::Megabytes(val)),
-                    Some("KB") | Some("kB") => Some(FolderSize::Kilobytes(val)),
-                    Some("B") => Some(FolderSize::Bytes(val)),
-                    _ => None
-                }
-            })
-            .ok_or("Not a valid size".into())
+        let s = s.trim();
+        let split = s.find(|c: char| c.is_alphabetic()).unwrap_or(s.len());
+        let value = try!(s[..split].trim().replace(',', ".").parse::<f64>()
+            .map_err(|_| format!("Not a valid size: {}", s)));
+        let unit = s[split..].trim().to_uppercase();
+        match SIZE_UNITS.iter().position(|u| *u == unit) {
+            Some(exp) if value >= 0.0 => {
+                Ok(FolderSize::from_bytes((value * 1024f64.powi(exp as i32)).round() as u64))
+            },
+            _ => Err(format!("Not a valid size: {}", s))
+        }
     }
 }
 
@@ -132,7 +305,8 @@ pub struct FolderInfo {
     pub has_wildcards: bool,
     pub has_pin: Option<i32>, // TODO: Is this correct? I have no idea.
     pub recordings_count: usize,
-    session_headers: Option<Headers>,
+    destructive_allowed: bool,
+    session: Option<Session>,
 }
 impl Decodable for FolderInfo {
     fn decode<D : Decoder>(d: &mut D) -> Result<FolderInfo, D::Error> {
@@ -167,61 +341,160 @@ impl Decodable for FolderInfo {
                     }
                 },
                 recordings_count: json_field!("recordings_count", d),
-                session_headers: None,
+                destructive_allowed: false,
+                session: None,
             })
         })
     }
 }
 
 impl FolderInfo {
-    fn root(rec_count: usize) -> FolderInfo {
+    /// Creates information of a folder that is not bound to a session
+    pub fn new(id: FolderId, name: &str) -> FolderInfo {
         FolderInfo {
-            id: FolderId::Root,
-            name: "Root".into(),
-            size: FolderSize::Bytes(0.0),
+            id: id,
+            name: name.into(),
+            size: FolderSize::default(),
             has_unwatched: false,
             has_wildcards: false,
             has_pin: None,
-            recordings_count: rec_count,
-            session_headers: None,
+            recordings_count: 0,
+            destructive_allowed: false,
+            session: None,
         }
     }
+    /// Returns the id of this folder
+    pub fn id(&self) -> FolderId {
+        self.id
+    }
+    /// Returns the session this folder is bound to
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+    /// Binds this folder to `session`
+    pub fn bind(&mut self, session: &Session) {
+        self.session = Some(session.clone());
+    }
+    /// Returns true if this folder is protected with a PIN and deleting its recordings
+    /// has not been allowed with `allow_destructive`
+    pub fn is_locked(&self) -> bool {
+        self.has_pin.is_some() && !self.destructive_allowed
+    }
+    /// Allows deleting the recordings of this folder even though it is protected with a PIN
+    /// No PIN is sent to Elisa Viihde, this only confirms that the caller means it.
+    /// Folders fetched from this information allow deleting as well.
+    pub fn allow_destructive(&mut self) {
+        self.destructive_allowed = true;
+    }
+    /// Iterates lazily over the pages of this folder's listing
+    /// Pages are fetched until `recordings_count` recordings have been listed, so callers
+    /// that only need the first recordings can stop early. The recordings count of the
+    /// root folder is not known, so its pages are fetched until one lists no new recordings.
+    pub fn pages(&self) -> Pages {
+        Pages {
+            info: self.clone(),
+            page: 0,
+            seen: HashSet::new(),
+            done: false,
+        }
+    }
+    fn root(rec_count: usize) -> FolderInfo {
+        let mut root = FolderInfo::new(FolderId::Root, "Root");
+        root.recordings_count = rec_count;
+        root
+    }
 }
 impl Fetch for FolderInfo {
     type Output = Folder;
     fn fetch_into(self) -> Result<Folder, EVError> {
         self.fetch()
     }
-    #[cfg(not(test))]
+    /// Fetches all pages of the folder
     fn fetch(&self) -> Result<Folder, EVError> {
-        let url = EVUrl::Folder(self.id);
-        let client = Client::new();
-        let res = client.get(url).headers(self.session_headers.clone().unwrap()).send();
-        res.map_err(EVError::from).and_then(|mut res| {
-            let mut ok = String::new();
-            try!(res.read_to_string(&mut ok));
-            json::decode(&ok)
-                .map_err(EVError::from)
-                .and_then(|mut f: Folder| {
-                    f.info = self.clone();
-                    for finfo in f.folders.iter_mut() {
-                        finfo.session_headers = self.session_headers.clone();
-                    }
-                    for rinfo in f.recordings.iter_mut() {
-                        rinfo.session_headers = self.session_headers.clone();
-                    }
-                    Ok(f)
-                })
-        })
+        let mut pages = self.pages();
+        let mut f = match pages.next() {
+            Some(page) => try!(page),
+            None => return Err(EVError::NotFound)
+        };
+        for page in pages {
+            let page = try!(page);
+            for finfo in page.folders {
+                if !f.folders.iter().any(|other| other.id == finfo.id) {
+                    f.folders.push(finfo);
+                }
+            }
+            f.recordings.extend(page.recordings);
+        }
+        Ok(f)
     }
-    #[cfg(test)]
-    fn fetch(&self) -> Result<Folder, EVError> {
-        use std::io::BufReader;
-        let file = File::open(format!("testdata/folder_{}.json", self.id)).unwrap();
-        let line = BufReader::new(file).lines().next().unwrap().unwrap();
-        let mut fldr: Folder = json::decode(&line).unwrap();
-        fldr.info = self.clone();
-        Ok(fldr)
+}
+
+/// Iterator over the pages of a folder listing
+pub struct Pages {
+    info: FolderInfo,
+    page: usize,
+    /// Ids of the recordings listed so far
+    seen: HashSet<i32>,
+    done: bool,
+}
+
+impl Pages {
+    /// Iterates over the recordings of the pages, fetching the next page only when needed
+    pub fn recordings(self) -> Box<dyn Iterator<Item=Result<RecordingInfo, EVError>>> {
+        Box::new(self.flat_map(|page| {
+            let recordings: Vec<Result<RecordingInfo, EVError>> = match page {
+                Ok(folder) => folder.recordings.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)]
+            };
+            recordings.into_iter()
+        }))
+    }
+    fn fetch_page(&self) -> Result<Folder, EVError> {
+        let session = try!(self.info.session.as_ref().ok_or(EVError::NoSession));
+        let url = EVUrl::folder_page(self.info.id, self.page);
+        let ok = try!(session.get(url.clone()));
+        let mut f: Folder = try!(decode_response(url, &ok));
+        f.info = self.info.clone();
+        f.bind(session);
+        let locked = f.info.is_locked();
+        for rinfo in f.recordings.iter_mut() {
+            rinfo.locked = locked;
+        }
+        Ok(f)
+    }
+}
+
+impl Iterator for Pages {
+    type Item = Result<Folder, EVError>;
+    fn next(&mut self) -> Option<Result<Folder, EVError>> {
+        if self.done {
+            return None;
+        }
+        let page = self.fetch_page();
+        self.page += 1;
+        match page {
+            Ok(mut f) => {
+                // Pages overlap when the listing changes between requests
+                let seen = &mut self.seen;
+                f.recordings.retain(|r| seen.insert(r.id));
+                // A page without new recordings ends the listing even if it is shorter than promised
+                if self.page > 1 && f.recordings.is_empty() {
+                    self.done = true;
+                    return None;
+                }
+                // The root folder does not report its number of recordings, so only
+                // a page without new recordings ends its listing
+                self.done = match self.info.id {
+                    FolderId::Root => false,
+                    _ => self.seen.len() >= self.info.recordings_count
+                };
+                Some(Ok(f))
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
     }
 }
 
@@ -230,26 +503,13 @@ impl Fetch for RecordingInfo {
     fn fetch_into(self) -> Result<Recording, EVError> {
         self.fetch()
     }
-    #[cfg(not(test))]
     fn fetch(&self) -> Result<Recording, EVError> {
+        let session = try!(self.session.as_ref().ok_or(EVError::NoSession));
         let url = EVUrl::Program(ProgramId::ProgramId(self.program_id));
-        let client = Client::new();
-        let res = client.get(url).headers(self.session_headers.clone().unwrap()).send();
-        res.map_err(EVError::from)
-            .and_then(|mut res| {
-                let mut ok = String::new();
-                try!(res.read_to_string(&mut ok).map_err(|e| EVError::from(e)));
-                json::decode(&ok).map_err(EVError::from)
-            })
-    }
-
-    #[cfg(test)]
-    fn fetch(&self) -> Result<Recording, EVError> {
-        use std::io::BufReader;
-        let file = File::open(format!("testdata/recording_{}.json", self.program_id)).unwrap();
-        let line = BufReader::new(file).lines().next().unwrap().unwrap();
-        let mut rec: Recording = json::decode(&line).unwrap();
+        let ok = try!(session.get(url.clone()));
+        let mut rec: Recording = try!(decode_response(url, &ok));
         rec.info = self.clone();
+        rec.info.programviewid = Some(rec.programviewid);
         Ok(rec)
     }
 }
@@ -264,34 +524,14 @@ pub struct Folder {
 }
 
 /// Folder's IntoIterator implementation iterates over all recordings
-/// of all folders starting from and including the folder into_iter() is called to
+/// of all folders starting from and including the folder into_iter() is called to.
+/// Folders that can not be fetched are yielded as errors, see `Folder::walk` for other options.
 impl IntoIterator for Folder {
-    type Item = RecordingInfo;
-    type IntoIter =
-        Chain<
-            FlatMap<
-                Filter<
-                    Map<
-                        vec::IntoIter<FolderInfo>,
-                        fn(FolderInfo) -> Result<Folder, EVError>
-                    >,
-                    fn(&Result<Folder, EVError>) -> bool
-                >,
-                vec::IntoIter<RecordingInfo>,
-                fn(Result<Folder, EVError>) -> vec::IntoIter<RecordingInfo>
-            >,
-            vec::IntoIter<RecordingInfo>
-        >;
-    fn into_iter(self) -> Self::IntoIter {
-        self.folders.into_iter()
-            .map(<FolderInfo as Fetch>::fetch_into as fn(FolderInfo) -> Result<Folder, EVError>)
-            .filter(Result::<Folder, EVError>::is_ok as fn(&Result<Folder, EVError>) -> bool)
-            .flat_map(into_iter_unwrapper as fn(Result<Folder, EVError>) -> vec::IntoIter<RecordingInfo>)
-            .chain(self.recordings.into_iter())
-    }
-}
-fn into_iter_unwrapper(f: Result<Folder, EVError>) -> vec::IntoIter<RecordingInfo> {
-    f.ok().unwrap().recordings.into_iter()
+    type Item = Result<RecordingInfo, EVError>;
+    type IntoIter = WalkRecordings;
+    fn into_iter(self) -> WalkRecordings {
+        self.walk(Default::default()).recordings()
+    }
 }
 
 /// Iterator over folders in another folder
@@ -306,6 +546,10 @@ pub struct Recordings<'a> {
 }
 
 impl<'a> Folder {
+    /// Returns the information of this folder
+    pub fn info(&'a self) -> &'a FolderInfo {
+        &self.info
+    }
     /// Returns `Folders` over this folder
     pub fn folders(&'a self) -> Folders<'a> {
         Folders { index: 0, folder: self }
@@ -314,6 +558,58 @@ impl<'a> Folder {
     pub fn recordings(&'a self) -> Recordings<'a> {
         Recordings { index: 0, folder: self }
     }
+    /// Moves all recordings of this folder to `destination`
+    /// Returns the result of each move in the order of the recordings.
+    /// Recordings that were moved are removed from this folder, the ones that failed to move are kept.
+    pub fn move_recordings_to(&mut self, destination: &FolderInfo) -> Vec<Result<RecordingInfo, EVError>> {
+        let recordings = mem::take(&mut self.recordings);
+        let mut results = Vec::with_capacity(recordings.len());
+        for rinfo in recordings {
+            let res = rinfo.move_to(destination);
+            if res.is_err() {
+                self.recordings.push(rinfo);
+            }
+            results.push(res);
+        }
+        let moved = results.iter().filter(|r| r.is_ok()).count();
+        self.info.recordings_count = self.info.recordings_count.saturating_sub(moved);
+        results
+    }
+    /// Deletes the recordings of this folder with `ids`
+    /// Returns the result of each deletion in the order of `ids`. Deleted recordings are
+    /// removed from this folder. Nothing is deleted if the folder is protected with a PIN
+    /// and deleting has not been allowed with `allow_destructive`.
+    pub fn delete_recordings(&mut self, ids: &[i32]) -> Result<Vec<Result<RecordingInfo, EVError>>, EVError> {
+        if self.info.is_locked() {
+            return Err(EVError::Locked(self.info.id));
+        }
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            let idx = match self.recordings.iter().position(|r| r.id == *id) {
+                Some(idx) => idx,
+                None => {
+                    results.push(Err(EVError::NotFound));
+                    continue;
+                }
+            };
+            results.push(match self.recordings[idx].delete() {
+                Ok(()) => {
+                    self.info.recordings_count = self.info.recordings_count.saturating_sub(1);
+                    Ok(self.recordings.remove(idx))
+                },
+                Err(e) => Err(e)
+            });
+        }
+        Ok(results)
+    }
+    /// Allows deleting the recordings of this folder even though it is protected with a PIN
+    /// See `FolderInfo::allow_destructive`.
+    pub fn allow_destructive(&mut self) {
+        self.info.allow_destructive();
+        for rinfo in self.recordings.iter_mut() {
+            rinfo.locked = false;
+        }
+    }
 }
 
 impl fmt::Display for Folder {
@@ -362,28 +658,28 @@ impl<'a> Iterator for Recordings<'a> {
 
 impl Folder {
     /// Fetches the root folder from Elisa Viihde
-    /// The `CookieJar` needs to have a valid `SetCookie` that has the session token.
-    /// You probably want to use `authentication::login` instead of this function.
-    #[cfg(not(test))]
-    pub fn fetch_root(jar: CookieJar) -> Result<Folder, EVError> {
-        let headers = evimproved_headers(Some(jar));
-        let client = Client::new();
-        let ret = match client.get(EVUrl::Folder(FolderId::Root)).headers(headers.clone()).send() {
-            Ok(mut res) => {
-                let mut ok = String::new();
-                try!(res.read_to_string(&mut ok));
-                let mut folder: Folder = try!(json::decode(&ok));
-                for finfo in folder.folders.iter_mut() {
-                    finfo.session_headers = Some(headers.clone());
-                }
-                for rinfo in folder.recordings.iter_mut() {
-                    rinfo.session_headers = Some(headers.clone());
-                }
-                Ok(folder)
-            },
-            Err(e) => Err(EVError::from(e))
-        };
-        ret
+    /// The `Session` needs to have a cookie that has the session token.
+    /// You probably want to use `Session::root` instead of this function.
+    pub fn fetch_root(session: &Session) -> Result<Folder, EVError> {
+        let mut info = FolderInfo::root(0);
+        info.bind(session);
+        let mut folder = try!(info.fetch());
+        folder.info.recordings_count = folder.recordings.len();
+        Ok(folder)
+    }
+    /// Walks the tree under and including this folder
+    pub fn walk(&self, options: WalkOptions) -> Walk {
+        Walk::new(self, options)
+    }
+    /// Binds this folder and the folders and recordings in it to `session`
+    pub fn bind(&mut self, session: &Session) {
+        self.info.bind(session);
+        for finfo in self.folders.iter_mut() {
+            finfo.bind(session);
+        }
+        for rinfo in self.recordings.iter_mut() {
+            rinfo.bind(session);
+        }
     }
     fn decode_folder<D : Decoder>(d: &mut D) -> Result<Folder, D::Error> {
         let recordings: Vec<RecordingInfo> = json_field!("recordings", d);
@@ -394,48 +690,43 @@ impl Folder {
         })
     }
     /// Recursively finds a folder under this folder with a name
-    /// If multiple folders match, the returned folder is the first that was found
+    /// If multiple folders match, the returned folder is the first that was found.
+    /// The tree is fetched with at most `DEFAULT_MAX_IN_FLIGHT` concurrent requests.
     pub fn find_by_name(&self, name: &str) -> Result<FolderInfo, EVError> {
-        fn do_find(name: &str, folder: &Folder, found: Arc<AtomicBool>) -> Result<FolderInfo, EVError> {
-            for finfo in folder.folders() {
-                if &finfo.name == name {
-                    found.store(true, Ordering::SeqCst);
-                    return Ok(finfo.clone())
+        self.find_by_name_with(name, DEFAULT_MAX_IN_FLIGHT)
+    }
+    /// Recursively finds a folder under this folder with a name
+    /// fetching at most `max_in_flight` folders at a time
+    /// The remaining fetches are cancelled once the folder is found. If the folder is
+    /// not found, the error of the first folder that could not be fetched is returned.
+    pub fn find_by_name_with(&self, name: &str, max_in_flight: usize) -> Result<FolderInfo, EVError> {
+        let mut pool = FetchPool::new(max_in_flight);
+        let mut error = None;
+        let search = |folder: &Folder, pool: &mut FetchPool| {
+            match folder.folders().find(|finfo| finfo.name == name) {
+                Some(finfo) => Some(finfo.clone()),
+                None => {
+                    for finfo in folder.folders() {
+                        pool.submit(finfo.clone());
+                    }
+                    None
                 }
             }
-            let mut threads = Vec::with_capacity(folder.folders().size_hint().1.unwrap());
-            for finfo in folder.folders() {
-                let (tx, rx) = channel();
-                let fi: FolderInfo = finfo.clone();
-                let n = name.to_owned();
-                let found = found.clone();
-                let t = thread::spawn(move || {
-                    let ret = match fi.fetch() {
-                        Ok(fldr) => {
-                            if found.load(Ordering::SeqCst) {
-                                Err(EVError::NotFound)
-                            }
-                            else {
-                                do_find(&n, &fldr, found)
-                            }
-                        }
-                        Err(e) => Err(e)
-                    };
-                    let _ = tx.send(ret);
-                });
-                threads.push((t, rx));
-            }
-            for (thread, rx) in threads {
-                match try!(rx.recv()) {
-                    Ok(ret) => return Ok(ret),
-                    _ => {}
-                };
-                let _ = thread.join();
+        };
+        if let Some(found) = search(self, &mut pool) {
+            return Ok(found);
+        }
+        while let Some((_, loaded)) = pool.next_loaded() {
+            match loaded {
+                Ok(folder) => {
+                    if let Some(found) = search(&folder, &mut pool) {
+                        return Ok(found);
+                    }
+                },
+                Err(e) => if error.is_none() { error = Some(e) }
             }
-            Err(EVError::NotFound)
         }
-        let found = Arc::new(AtomicBool::new(false));
-        do_find(name, self, found)
+        Err(error.unwrap_or(EVError::NotFound))
     }
 }
 
@@ -444,7 +735,9 @@ impl Decodable for Folder {
         d.read_struct("", 0, |d| {
             d.read_struct_field("ready_data", 0, |rd| {
                 rd.read_seq(|rd, len| {
-                    assert!(len==1);
+                    if len != 1 {
+                        return Err(rd.error(&format!("{}ready_data has {} elements instead of 1", UNEXPECTED_SHAPE, len)));
+                    }
                     rd.read_seq_elt(0, |rd| { Folder::decode_folder(rd) })
                 })
             })
@@ -453,6 +746,7 @@ impl Decodable for Folder {
 }
 
 /// Id of a program in Elisa Viihde
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ProgramId {
     ProgramId(i32)
 }
@@ -464,6 +758,15 @@ impl fmt::Display for ProgramId {
     }
 }
 
+/// Wildcard recording rule that recorded a program
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WildcardInfo {
+    /// Pattern the name of the program matched, like `*uutiset*`
+    pub pattern: String,
+    /// Channel the rule records from, empty for all channels
+    pub channel: String,
+}
+
 /// Recording in Elisa Viihde
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -472,14 +775,34 @@ pub struct Recording {
     pub id: i32,
     pub name: String,
     pub channel: String,
-    pub length: i32,
-    pub start_time: String,
-    pub end_time: String,
+    /// The channel is encrypted and the recording can not be downloaded as is
+    pub scrambled_channel: bool,
+    pub short_text: String,
+    pub description: String,
+    pub length: Duration,
+    /// Length formatted by Elisa Viihde, like `5 min`
+    pub flength: String,
+    pub start_time: Timestamp,
+    pub end_time: Timestamp,
+    pub has_started: bool,
+    pub has_ended: bool,
+    pub recorded: bool,
+    /// The recording can be watched
+    pub ready: bool,
+    /// Wildcard rule of the recording, if it was recorded by one
+    pub wildcard: Option<WildcardInfo>,
     pub url: Url,
     pub programviewid: i32,
     pub recordingid: i32
 }
 
+impl Recording {
+    /// Returns true if the program has started but not yet ended
+    pub fn is_in_progress(&self) -> bool {
+        self.has_started && !self.has_ended
+    }
+}
+
 /// Contains information of a Recording
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
@@ -489,11 +812,17 @@ pub struct RecordingInfo {
     pub folder_id : Option<i32>,
     pub name: String,
     pub channel: String,
-    pub start_time: String, // TODO
-    pub timestamp: String, // TODO
+    pub start_time: Timestamp,
+    pub timestamp: Timestamp,
     pub viewcount: i32,
     pub length: i32,
-    session_headers: Option<Headers>,
+    /// The program view id moves and deletes are requested with
+    /// The folder listing does not have it, it is known once the details of the recording
+    /// have been fetched.
+    pub programviewid: Option<i32>,
+    /// The recording is in a folder that is protected with a PIN
+    locked: bool,
+    session: Option<Session>,
 }
 
 impl Default for RecordingInfo {
@@ -504,11 +833,66 @@ impl Default for RecordingInfo {
             folder_id: None,
             name: "".to_string(),
             channel: "".to_string(),
-            start_time: "".to_string(),
-            timestamp: "".to_string(),
+            start_time: Default::default(),
+            timestamp: Default::default(),
             viewcount: 0,
             length: 0,
-            session_headers: None,
+            programviewid: None,
+            locked: false,
+            session: None,
+        }
+    }
+}
+
+impl RecordingInfo {
+    /// Returns the session this recording is bound to
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+    /// Binds this recording to `session`
+    pub fn bind(&mut self, session: &Session) {
+        self.session = Some(session.clone());
+    }
+    /// Moves this recording to `destination`
+    /// The details of the recording are fetched first if its `programviewid` is not known.
+    /// Returns the information of the moved recording with an updated `folder_id`.
+    pub fn move_to(&self, destination: &FolderInfo) -> Result<RecordingInfo, EVError> {
+        let programviewid = try!(self.programviewid());
+        try!(self.send_command(EVUrl::Move(ProgramId::ProgramId(programviewid), destination.id)));
+        let mut moved = self.clone();
+        moved.programviewid = Some(programviewid);
+        moved.folder_id = match destination.id {
+            FolderId::Root => None,
+            FolderId::FolderId(id) => Some(id)
+        };
+        Ok(moved)
+    }
+    /// Deletes this recording from Elisa Viihde
+    /// Recordings in folders protected with a PIN are only deleted if
+    /// `FolderInfo::allow_destructive` was called before the folder was fetched. Like moving,
+    /// the details of the recording are fetched first if its `programviewid` is not known.
+    pub fn delete(&self) -> Result<(), EVError> {
+        if self.locked {
+            let folder = self.folder_id.map(FolderId::FolderId).unwrap_or(FolderId::Root);
+            return Err(EVError::Locked(folder));
+        }
+        let programviewid = try!(self.programviewid());
+        self.send_command(EVUrl::Delete(ProgramId::ProgramId(programviewid)))
+    }
+    fn programviewid(&self) -> Result<i32, EVError> {
+        match self.programviewid {
+            Some(id) => Ok(id),
+            None => Ok(try!(self.fetch()).programviewid)
+        }
+    }
+    fn send_command(&self, url: EVUrl) -> Result<(), EVError> {
+        let session = try!(self.session.as_ref().ok_or(EVError::NoSession));
+        let ok = try!(session.get(url.clone()));
+        // Elisa Viihde answers TRUE when the recording was moved or deleted and FALSE when it refused
+        match ok.trim() {
+            "TRUE" => Ok(()),
+            "FALSE" => Err(EVError::fetch(url, EVError::Refused)),
+            other => Err(EVError::fetch(url, EVError::UnexpectedShape(format!("{} is not TRUE or FALSE", other))))
         }
     }
 }
@@ -523,16 +907,15 @@ impl Decodable for RecordingInfo {
                     let idstr: String = json_field!("folder_id", d);
                     str::parse::<i32>(&idstr).ok()
                 },
-                name: {
-                    let percent_encoded_str : String = json_field!("name", d);
-                    String::from_utf8(percent_decode(percent_encoded_str.as_bytes())).unwrap()
-                },
+                name: try!(percent_decoded_field("name", d)),
                 channel: json_field!("channel", d),
                 start_time: json_field!("start_time", d),
                 timestamp: json_field!("timestamp", d),
                 viewcount: json_field!("viewcount", d),
                 length: json_field!("length", d),
-                session_headers: None
+                programviewid: None,
+                locked: false,
+                session: None
             })
         })
     }
@@ -541,17 +924,35 @@ impl Decodable for RecordingInfo {
 impl Decodable for Recording {
     fn decode<D : Decoder>(d: &mut D) -> Result<Recording, D::Error> {
         d.read_struct("", 0, |d| {
+            let length: i64 = json_field!("length", d);
+            let wildcard = if try!(bool_field("is_wildcard", d)) {
+                let pattern: Option<String> = json_field!("wildcard", d);
+                let channel: Option<String> = json_field!("wildcard_channel", d);
+                Some(WildcardInfo {
+                    pattern: pattern.unwrap_or_default(),
+                    channel: channel.unwrap_or_default(),
+                })
+            }
+            else {
+                None
+            };
             Ok(Recording {
                 info: Default::default(),
                 id: json_field!("id", d),
-                name: {
-                    let percent_encoded_str : String = json_field!("name", d);
-                    String::from_utf8(percent_decode(percent_encoded_str.as_bytes())).unwrap()
-                },
+                name: try!(percent_decoded_field("name", d)),
                 channel: json_field!("channel", d),
-                length: json_field!("length", d),
+                scrambled_channel: try!(bool_field("scrambled_channel", d)),
+                short_text: try!(percent_decoded_field("short_text", d)),
+                description: try!(percent_decoded_field("description", d)),
+                length: Duration::minutes(length),
+                flength: json_field!("flength", d),
                 start_time: json_field!("start_time", d),
                 end_time: json_field!("end_time", d),
+                has_started: try!(bool_field("has_started", d)),
+                has_ended: try!(bool_field("has_ended", d)),
+                recorded: try!(bool_field("recorded", d)),
+                ready: try!(bool_field("ready", d)),
+                wildcard: wildcard,
                 url: json_field!("url", d),
                 programviewid: json_field!("programviewid", d),
                 recordingid: json_field!("recordingid", d)
@@ -564,9 +965,27 @@ impl Decodable for Recording {
 mod tests {
     use rustc_serialize::json;
     use std::io::BufReader;
-    use super::{Recording, Folder, FolderId, FolderSize};
+    use super::{EVError, Recording, Folder, FolderId, FolderInfo, FolderSize, ProgramId};
+    use urls::EVUrl;
+    use std::error::Error;
+    use session::Session;
+    use traits::Fetch;
+    use transport::{FixtureTransport, Transport};
     use std::io::BufRead;
     use std::fs::File;
+    use std::sync::Arc;
+
+    fn fixture_session() -> Session {
+        Session::with_transport(FixtureTransport::from_dir("testdata").unwrap())
+    }
+
+    /// Returns the urls of the moves and deletes sent through `transport`
+    fn commands(transport: &FixtureTransport) -> Vec<EVUrl> {
+        transport.requests().into_iter()
+            .map(|r| r.url)
+            .filter(|url| matches!(*url, EVUrl::Move(..) | EVUrl::Delete(..)))
+            .collect()
+    }
 
     macro_rules! setup_test(
         ($filename:expr, $code:expr) => {
@@ -611,9 +1030,8 @@ mod tests {
                     "Foldername" | "Test folder" => {},
                     _ => assert!(false, "Folder name was invalid")
                 }
-                println!("{:?}", fldr.size);
-                match fldr.size {
-                    FolderSize::Gigabytes(57.31) | FolderSize::Gigabytes(865.87) => {},
+                match &fldr.size.to_string()[..] {
+                    "57.31 GB" | "865.87 GB" => {},
                     _ => assert!(false, "Invalid size")
                 }
             }
@@ -643,9 +1061,15 @@ mod tests {
             assert!(r.id == 1000001);
             assert!(r.name == "Tämä on testi"); // Finnish characters used on purpose
             assert!(r.channel == "MTV3");
-            assert!(r.length == 5);
-            // start_time
-            // end_time
+            assert!(r.length.num_minutes() == 5);
+            assert!(r.flength == "5 min");
+            assert!(!r.scrambled_channel);
+            assert!(r.has_started && r.has_ended && r.recorded && r.ready);
+            assert!(!r.is_in_progress());
+            let wildcard = r.wildcard.unwrap();
+            assert!(wildcard.pattern == "*testi*" && wildcard.channel == "MTV3");
+            assert!(r.start_time.raw() == "18.9.2013 12:35:00");
+            assert!(r.end_time.timespec().unwrap().sec - r.start_time.timespec().unwrap().sec == 5 * 60);
             assert!(r.url.to_string() == "http://google.fi/");
             assert!(r.programviewid == 123456789);
             assert!(r.recordingid == 987654321);
@@ -653,13 +1077,234 @@ mod tests {
     }
 
     #[test]
-    fn able_to_find_a_folder_by_name() {
+    fn able_to_parse_recording_in_progress() {
+        let r: Recording = json::decode("{ \"id\": \"1\", \"name\": \"Uutiset\", \"channel\": \"Yle TV1\", \"scrambled_channel\": \"true\", \"short_text\": \"P%C3%A4iv%C3%A4n%20uutiset\", \"description\": \"\", \"length\": \"90\", \"flength\": \"1 h 30 min\", \"start_time\": \"18.9.2013 12:35:00\", \"end_time\": \"18.9.2013 14:05:00\", \"has_started\": \"true\", \"has_ended\": \"false\", \"recorded\": \"false\", \"ready\": \"false\", \"is_wildcard\": \"false\", \"url\": \"http://google.fi\", \"programviewid\": \"2\", \"recordingid\": \"3\" }").unwrap();
+        assert!(r.is_in_progress());
+        assert!(r.scrambled_channel && !r.ready);
+        assert!(r.short_text == "Päivän uutiset");
+        assert!(r.length == ::time::Duration::minutes(90));
+        assert!(r.wildcard.is_none());
+        let mut program = String::new();
+        BufReader::new(File::open("testdata/recording_1000003.json").unwrap()).read_line(&mut program).unwrap();
+        assert!(json::decode::<Recording>(&program).is_ok());
+        let invalid = program.replace("\"is_wildcard\": \"false\"", "\"is_wildcard\": \"maybe\"");
+        assert!(invalid != program);
+        match json::decode::<Recording>(&invalid) {
+            Err(json::DecoderError::ApplicationError(ref msg)) => assert!(msg.ends_with("is_wildcard is not a boolean")),
+            _ => assert!(false, "is_wildcard should only be true or false")
+        }
+    }
+
+    #[test]
+    fn able_to_move_recordings() {
+        let transport = Arc::new(FixtureTransport::from_dir("testdata").unwrap());
+        let url = EVUrl::Move(ProgramId::ProgramId(123456789), FolderId::FolderId(1000001));
+        transport.insert(url.clone(), "TRUE");
+        let f = Session::with_transport(transport.clone()).root().unwrap();
+        let destination = f.find_by_name("Foldername").unwrap();
+        let rec = f.recordings().next().unwrap();
+        assert!(rec.folder_id.is_none());
+        let moved = rec.move_to(&destination).unwrap();
+        assert!(moved.program_id == rec.program_id);
+        assert!(moved.folder_id == Some(1000001));
+        assert!(commands(&transport) == vec![url]);
+    }
+
+    #[test]
+    fn fetched_recordings_are_moved_without_fetching_again() {
+        let transport = Arc::new(FixtureTransport::from_dir("testdata").unwrap());
+        let url = EVUrl::Move(ProgramId::ProgramId(123456789), FolderId::FolderId(1000001));
+        transport.insert(url.clone(), "TRUE");
+        let f = Session::with_transport(transport.clone()).root().unwrap();
+        let destination = f.find_by_name("Foldername").unwrap();
+        let rec = f.recordings().next().unwrap().fetch().unwrap().info;
+        assert!(rec.programviewid == Some(123456789));
+        let moved = rec.move_to(&destination).unwrap();
+        assert!(moved.programviewid == Some(123456789));
+        let programs = transport.requests().into_iter().filter(|r| matches!(r.url, EVUrl::Program(..))).count();
+        assert!(programs == 1);
+        assert!(commands(&transport) == vec![url]);
+    }
+
+    #[test]
+    fn able_to_move_all_recordings_of_a_folder() {
+        let transport = Arc::new(FixtureTransport::from_dir("testdata").unwrap());
+        let url = EVUrl::Move(ProgramId::ProgramId(123456791), FolderId::FolderId(1000002));
+        transport.insert(url.clone(), "TRUE");
+        let f = Session::with_transport(transport.clone()).root().unwrap();
+        let destination = f.find_by_name("Test folder").unwrap();
+        let mut source = f.find_by_name("Foldername").and_then(Fetch::fetch_into).unwrap();
+        let results = source.move_recordings_to(&destination);
+        assert!(results.len() == 1);
+        assert!(results.iter().all(|r| r.as_ref().map(|r| r.folder_id == Some(1000002)).unwrap_or(false)));
+        assert!(source.recordings().next().is_none());
+        assert!(commands(&transport) == vec![url]);
+    }
+
+    #[test]
+    fn able_to_delete_recordings() {
+        let transport = Arc::new(FixtureTransport::from_dir("testdata").unwrap());
+        let url = EVUrl::Delete(ProgramId::ProgramId(123456791));
+        transport.insert(url.clone(), "TRUE");
+        let f = Session::with_transport(transport.clone()).root().unwrap();
+        let mut source = f.find_by_name("Foldername").and_then(Fetch::fetch_into).unwrap();
+        let id = source.recordings().next().unwrap().id;
+        let results = source.delete_recordings(&[id, -1]).unwrap();
+        assert!(commands(&transport) == vec![url]);
+        assert!(results[0].as_ref().map(|r| r.id == id).unwrap_or(false));
+        match results[1] {
+            Err(EVError::NotFound) => {},
+            _ => assert!(false, "Missing recordings should not be deleted")
+        }
+        assert!(source.recordings().next().is_none());
+    }
+
+    #[test]
+    fn locked_folders_are_not_deleted_from() {
+        let transport = Arc::new(FixtureTransport::from_dir("testdata").unwrap());
+        let url = EVUrl::Delete(ProgramId::ProgramId(123456791));
+        transport.insert(url.clone(), "TRUE");
+        let f = Session::with_transport(transport.clone()).root().unwrap();
+        let mut finfo = f.find_by_name("Foldername").unwrap();
+        finfo.has_pin = Some(1);
+        let mut locked = finfo.fetch().unwrap();
+        let id = locked.recordings().next().unwrap().id;
+        match locked.recordings().next().unwrap().delete() {
+            Err(EVError::Locked(FolderId::FolderId(1000001))) => {},
+            _ => assert!(false, "Recordings in locked folders should not be deleted")
+        }
+        assert!(locked.delete_recordings(&[id]).is_err());
+        assert!(commands(&transport).is_empty());
+        locked.allow_destructive();
+        assert!(locked.delete_recordings(&[id]).unwrap()[0].is_ok());
+        assert!(commands(&transport) == vec![url]);
+    }
+
+    #[test]
+    fn commands_must_be_confirmed() {
+        let transport = FixtureTransport::from_dir("testdata").unwrap();
+        transport.insert(EVUrl::Delete(ProgramId::ProgramId(123456789)), "FALSE");
+        transport.insert(EVUrl::Delete(ProgramId::ProgramId(123456790)), "<html></html>");
+        let f = Session::with_transport(transport).root().unwrap();
+        let mut recordings = f.recordings();
+        match recordings.next().unwrap().delete() {
+            Err(EVError::Fetch(_, ref e)) => match **e {
+                EVError::Refused => {},
+                _ => assert!(false, "FALSE should refuse the command")
+            },
+            _ => assert!(false, "FALSE should not delete the recording")
+        }
+        match recordings.next().unwrap().delete() {
+            Err(EVError::Fetch(_, ref e)) => match **e {
+                EVError::UnexpectedShape(_) => {},
+                _ => assert!(false, "Only TRUE should confirm the command")
+            },
+            _ => assert!(false, "An unknown answer should not delete the recording")
+        }
+    }
+
+    #[test]
+    fn unbound_values_can_not_be_fetched() {
         setup_test!("testdata/root_folder.json", |f : Folder| {
-            let f1 = f.find_by_name("Test folder");
-            assert!(f1.is_ok());
-            let f2 = f.find_by_name("Does not exist");
-            assert!(f2.is_err());
+            for finfo in f.folders() {
+                assert!(finfo.fetch().is_err());
+            }
         });
     }
-}
 
+    #[test]
+    fn fetch_errors_describe_the_request() {
+        let session = fixture_session();
+        let mut finfo = FolderInfo::new(FolderId::FolderId(1), "Missing");
+        finfo.bind(&session);
+        let e = finfo.fetch().unwrap_err();
+        assert!(e.to_string() == "Fetching folder 1 failed: Unexpected response status: 404 Not Found");
+        match e.source() {
+            Some(cause) => assert!(cause.to_string().starts_with("Unexpected response status")),
+            None => assert!(false, "The status should be the source of the error")
+        }
+    }
+
+    #[test]
+    fn malformed_responses_are_errors() {
+        let transport = FixtureTransport::new();
+        transport.insert(EVUrl::Folder(FolderId::Root), "{\"ready_data\":[]}");
+        transport.insert(EVUrl::Folder(FolderId::FolderId(1)),
+            "{\"ready_data\":[{\"folders\":[],\"recordings\":[{\"id\":1,\"program_id\":2,\"folder_id\":\"1\",\"name\":\"%FF\",\"channel\":\"\",\"start_time\":\"\",\"timestamp\":\"\",\"viewcount\":0,\"length\":0}]}]}");
+        let session = Session::with_transport(transport);
+        match session.root() {
+            Err(EVError::Fetch(_, ref e)) => match **e {
+                EVError::UnexpectedShape(_) => {},
+                _ => assert!(false, "Empty ready_data should have an unexpected shape")
+            },
+            _ => assert!(false, "Empty ready_data should not be decoded")
+        }
+        let mut finfo = FolderInfo::new(FolderId::FolderId(1), "Invalid");
+        finfo.bind(&session);
+        match finfo.fetch() {
+            Err(EVError::Fetch(_, ref e)) => match **e {
+                EVError::InvalidEncoding(ref field) => assert!(field == "name"),
+                _ => assert!(false, "Invalid UTF-8 should be an encoding error")
+            },
+            _ => assert!(false, "Invalid UTF-8 should not be decoded")
+        }
+    }
+
+    #[test]
+    fn overlapping_pages_are_merged() {
+        let recording = |id: i32| format!("{{\"id\":{},\"program_id\":{},\"folder_id\":\"1\",\"name\":\"Name\",\"channel\":\"\",\"start_time\":\"\",\"timestamp\":\"\",\"viewcount\":0,\"length\":0}}", id, id);
+        let page = |ids: &[i32]| format!("{{\"ready_data\":[{{\"folders\":[],\"recordings\":[{}]}}]}}",
+            ids.iter().map(|&id| recording(id)).collect::<Vec<_>>().join(","));
+        let transport = FixtureTransport::new();
+        transport.insert(EVUrl::Folder(FolderId::FolderId(1)), &page(&[1, 2]));
+        transport.insert(EVUrl::FolderPage(FolderId::FolderId(1), 1), &page(&[2, 3]));
+        let session = Session::with_transport(transport);
+        let mut finfo = FolderInfo::new(FolderId::FolderId(1), "Paged");
+        finfo.recordings_count = 3;
+        finfo.bind(&session);
+        let ids: Vec<i32> = finfo.fetch().unwrap().recordings().map(|r| r.id).collect();
+        assert!(ids == vec![1, 2, 3]);
+        let ids: Vec<i32> = finfo.pages().recordings().map(|r| r.unwrap().id).collect();
+        assert!(ids == vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn root_listing_ends_with_a_page_without_new_recordings() {
+        let transport = Arc::new(FixtureTransport::from_dir("testdata").unwrap());
+        let session = Session::with_transport(transport.clone());
+        assert!(session.root().unwrap().recordings().count() == 2);
+        let urls: Vec<EVUrl> = transport.requests().into_iter().map(|r| r.url).collect();
+        assert!(urls == vec![EVUrl::Folder(FolderId::Root), EVUrl::FolderPage(FolderId::Root, 1)]);
+
+        // A service that ignores the page number answers with the first page again
+        let first = transport.send(&transport.requests()[0]).unwrap().body;
+        transport.insert(EVUrl::FolderPage(FolderId::Root, 1), &first);
+        assert!(session.root().unwrap().recordings().count() == 2);
+        assert!(transport.requests().iter().filter(|r| r.url == EVUrl::FolderPage(FolderId::Root, 1)).count() == 2);
+    }
+
+    #[test]
+    fn able_to_find_a_folder_by_name() {
+        let f = fixture_session().root().unwrap();
+        let f1 = f.find_by_name("Test folder");
+        assert!(f1.is_ok());
+        let f2 = f.find_by_name("Does not exist");
+        assert!(f2.is_err());
+    }
+
+    #[test]
+    fn folder_sizes_add_up() {
+        let sizes: Vec<FolderSize> = ["57.31 GB", "865,87GB", "1 TB", "512 kB", "100 B"].iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert!(sizes[3].bytes() == 512 * 1024);
+        assert!(sizes[2] > sizes[1] && sizes[1] > sizes[0]);
+        let total: FolderSize = sizes.iter().sum();
+        assert!(total == sizes[0] + sizes[1] + sizes[2] + sizes[3] + sizes[4]);
+        assert!(total.to_string() == "1.90 TB");
+        assert!(sizes[4].to_string() == "100 B");
+        assert!(sizes[3].to_string() == "512.00 KB");
+        assert!("57.31 XB".parse::<FolderSize>().is_err());
+        assert!("".parse::<FolderSize>().is_err());
+    }
+}
@@ -6,11 +6,25 @@ use types::FolderId;
 use types::ProgramId;
 use std::fmt;
 
+/// Base url of the Elisa Viihde API
+pub const DEFAULT_BASE_URL: &'static str = "https://api.elisaviihde.fi/etvrecorder/";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum EVUrl {
     Login,
     Folder(FolderId),
+    /// A page after the first one of a folder listing
+    /// Pages are requested with `ppos` counting pages from 0. This has not been checked
+    /// against Elisa Viihde; a service that ignores `ppos` returns the first page again,
+    /// which ends the listing since the page has no new recordings.
+    FolderPage(FolderId, usize),
     Program(ProgramId),
-    Move(ProgramId, FolderId)
+    Move(ProgramId, FolderId),
+    /// Removes the recording with a program view id
+    /// Unlike the other urls this one has not been seen in Elisa Viihde's traffic; it follows
+    /// the form of `Move`. Deleting only succeeds when the service answers `TRUE`, so a wrong
+    /// guess fails instead of being reported as a deleted recording.
+    Delete(ProgramId),
 }
 
 impl hyper::client::IntoUrl for EVUrl {
@@ -21,18 +35,46 @@ impl hyper::client::IntoUrl for EVUrl {
     }
 }
 
-impl fmt::Display for EVUrl {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+impl EVUrl {
+    /// Returns the url of the `page`th page of the listing of `folder`, starting from 0
+    pub fn folder_page(folder: FolderId, page: usize) -> EVUrl {
+        match page {
+            0 => EVUrl::Folder(folder),
+            page => EVUrl::FolderPage(folder, page)
+        }
+    }
+    /// Returns the path and query of this url relative to the base url
+    pub fn path(&self) -> String {
         match *self {
-            EVUrl::Login => write!(fmt, "https://api.elisaviihde.fi/etvrecorder/login.sl"),
+            EVUrl::Login => "login.sl".to_string(),
             EVUrl::Folder(ref id) => match *id {
-                FolderId::Root => write!(fmt, "https://api.elisaviihde.fi/etvrecorder/ready.sl?ajax=true"),
-                ref id => write!(fmt, "https://api.elisaviihde.fi/etvrecorder/ready.sl?folderid={}&ppos=0&ajax=true", id),
+                FolderId::Root => "ready.sl?ajax=true".to_string(),
+                ref id => format!("ready.sl?folderid={}&ppos=0&ajax=true", id),
+            },
+            EVUrl::FolderPage(ref id, page) => match *id {
+                FolderId::Root => format!("ready.sl?ppos={}&ajax=true", page),
+                ref id => format!("ready.sl?folderid={}&ppos={}&ajax=true", id, page),
             },
-            EVUrl::Program(ref id) => write!(fmt, "https://api.elisaviihde.fi/etvrecorder/program.sl?programid={}&ppos=0&ajax=true", id),
-            EVUrl::Move(ref pid, ref fid) => write!(fmt, "https://api.elisaviihde.fi/etvrecorder/ready.sl?ajax=true&move=true&destination={}&programviewid={}", fid, pid)
+            EVUrl::Program(ref id) => format!("program.sl?programid={}&ppos=0&ajax=true", id),
+            EVUrl::Move(ref pid, ref fid) => format!("ready.sl?ajax=true&move=true&destination={}&programviewid={}", fid, pid),
+            EVUrl::Delete(ref pid) => format!("ready.sl?ajax=true&remove=true&programviewid={}", pid),
         }
     }
+    /// Returns this url relative to `base_url` instead of `DEFAULT_BASE_URL`
+    pub fn with_base(&self, base_url: &str) -> String {
+        if base_url.ends_with('/') {
+            format!("{}{}", base_url, self.path())
+        }
+        else {
+            format!("{}/{}", base_url, self.path())
+        }
+    }
+}
+
+impl fmt::Display for EVUrl {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.with_base(DEFAULT_BASE_URL))
+    }
 }
 
 #[cfg(test)]
@@ -70,4 +112,24 @@ mod tests {
         let url = EVUrl::Move(ProgramId::ProgramId(123), FolderId::FolderId(321));
         assert!(url.to_string() == "https://api.elisaviihde.fi/etvrecorder/ready.sl?ajax=true&move=true&destination=321&programviewid=123");
     }
+
+    #[test]
+    fn show_url_with_base() {
+        let url = EVUrl::Folder(FolderId::FolderId(123));
+        assert!(url.with_base("http://localhost:8080/etvrecorder") == "http://localhost:8080/etvrecorder/ready.sl?folderid=123&ppos=0&ajax=true");
+        assert!(EVUrl::Login.with_base("http://localhost:8080/") == "http://localhost:8080/login.sl");
+    }
+
+    #[test]
+    fn show_folder_page_url() {
+        assert!(EVUrl::folder_page(FolderId::FolderId(123), 0) == EVUrl::Folder(FolderId::FolderId(123)));
+        let url = EVUrl::folder_page(FolderId::FolderId(123), 2);
+        assert!(url.to_string() == "https://api.elisaviihde.fi/etvrecorder/ready.sl?folderid=123&ppos=2&ajax=true");
+    }
+
+    #[test]
+    fn show_delete_url() {
+        let url = EVUrl::Delete(ProgramId::ProgramId(123));
+        assert!(url.to_string() == "https://api.elisaviihde.fi/etvrecorder/ready.sl?ajax=true&remove=true&programviewid=123");
+    }
 }
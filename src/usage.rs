@@ -0,0 +1,234 @@
+/* vim: set et: */
+
+use pool::DEFAULT_MAX_IN_FLIGHT;
+use types::{EVError, Folder, FolderInfo, FolderSize, RecordingInfo};
+use walk::{Entry, ErrorPolicy, WalkOptions};
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::default::Default;
+
+/// Storage used by a folder
+#[derive(Clone, Debug)]
+pub struct FolderUsage {
+    pub info: FolderInfo,
+    /// Depth of the folder under the folder the usage was computed for
+    pub depth: usize,
+    /// Size Elisa Viihde reports for this folder
+    /// It is not known whether the size includes the folders under this one, so it is only
+    /// divided among the recordings directly in this folder.
+    pub size: FolderSize,
+    /// Estimated size of the recordings in this folder and all folders under it
+    /// Only the recordings are added up, so a folder without recordings of its own adds nothing.
+    pub total_size: FolderSize,
+    pub recordings: usize,
+    pub total_recordings: usize,
+    /// Length of the recordings directly in this folder in minutes
+    pub length: u64,
+    pub total_length: u64,
+}
+
+/// Storage used by a recording
+#[derive(Clone, Debug)]
+pub struct RecordingUsage {
+    pub info: RecordingInfo,
+    /// Share of the size of its folder in proportion to the length of the recording.
+    /// Elisa Viihde does not report the sizes of single recordings.
+    pub estimated_size: FolderSize,
+}
+
+/// Storage used by the recordings of a channel
+#[derive(Clone, Debug)]
+pub struct ChannelUsage {
+    pub channel: String,
+    pub recordings: usize,
+    /// Length of the recordings in minutes
+    pub length: u64,
+    pub estimated_size: FolderSize,
+}
+
+/// Storage usage of a folder tree
+#[derive(Clone, Debug)]
+pub struct Usage {
+    /// Folders in the order they were walked, the folder the usage was computed for first
+    pub folders: Vec<FolderUsage>,
+    pub recordings: Vec<RecordingUsage>,
+}
+
+impl Usage {
+    /// Computes the usage of the tree under and including `root`
+    /// The tree is fetched with at most `DEFAULT_MAX_IN_FLIGHT` concurrent requests.
+    pub fn of(root: &Folder) -> Result<Usage, EVError> {
+        let walk = root.walk(WalkOptions {
+            on_error: ErrorPolicy::Abort,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            ..Default::default()
+        });
+        let mut folders: Vec<FolderUsage> = Vec::new();
+        let mut parents: Vec<Option<usize>> = Vec::new();
+        let mut recordings: Vec<(usize, RecordingInfo)> = Vec::new();
+        // Indices of the folders from the root to the current folder
+        let mut path: Vec<usize> = Vec::new();
+        for entry in walk {
+            match entry {
+                Entry::Folder(info, depth) => {
+                    path.truncate(depth);
+                    parents.push(path.last().cloned());
+                    path.push(folders.len());
+                    folders.push(FolderUsage {
+                        size: info.size,
+                        total_size: FolderSize::default(),
+                        info: info,
+                        depth: depth,
+                        recordings: 0,
+                        total_recordings: 0,
+                        length: 0,
+                        total_length: 0,
+                    });
+                },
+                // In pre-order the recordings of a folder follow it directly
+                Entry::Recording(rinfo) => {
+                    let idx = *path.last().unwrap();
+                    let length = rinfo.length.max(0) as u64;
+                    folders[idx].recordings += 1;
+                    folders[idx].length += length;
+                    recordings.push((idx, rinfo));
+                },
+                Entry::Failed(_, e) => return Err(e)
+            }
+        }
+        let recordings: Vec<RecordingUsage> = recordings.into_iter()
+            .map(|(idx, rinfo)| {
+                let folder = &mut folders[idx];
+                let estimate = if folder.length > 0 {
+                    folder.size.bytes() as f64 * rinfo.length.max(0) as f64 / folder.length as f64
+                }
+                else {
+                    folder.size.bytes() as f64 / folder.recordings as f64
+                };
+                let estimated_size = FolderSize::from_bytes(estimate.round() as u64);
+                folder.total_size = folder.total_size + estimated_size;
+                RecordingUsage {
+                    info: rinfo,
+                    estimated_size: estimated_size,
+                }
+            })
+            .collect();
+        for f in folders.iter_mut() {
+            f.total_recordings = f.recordings;
+            f.total_length = f.length;
+        }
+        // Children come after their parents, so walking backwards adds up whole subtrees
+        for idx in (1..folders.len()).rev() {
+            if let Some(parent) = parents[idx] {
+                let (size, count, length) = (folders[idx].total_size, folders[idx].total_recordings, folders[idx].total_length);
+                let p = &mut folders[parent];
+                p.total_size = p.total_size + size;
+                p.total_recordings += count;
+                p.total_length += length;
+            }
+        }
+        Ok(Usage { folders: folders, recordings: recordings })
+    }
+    /// Returns the size of the whole tree
+    pub fn total_size(&self) -> FolderSize {
+        self.folders.first().map(|f| f.total_size).unwrap_or_default()
+    }
+    /// Returns the length of all recordings of the tree in minutes
+    pub fn total_length(&self) -> u64 {
+        self.folders.first().map(|f| f.total_length).unwrap_or(0)
+    }
+    /// Returns the `n` folders using the most space with the folders under them
+    /// The folder the usage was computed for is not included.
+    pub fn largest_folders(&self, n: usize) -> Vec<&FolderUsage> {
+        let mut folders: Vec<&FolderUsage> = self.folders.iter().skip(1).collect();
+        folders.sort_by_key(|f| Reverse(f.total_size));
+        folders.truncate(n);
+        folders
+    }
+    /// Returns the `n` recordings with the largest estimated size
+    pub fn largest_recordings(&self, n: usize) -> Vec<&RecordingUsage> {
+        let mut recordings: Vec<&RecordingUsage> = self.recordings.iter().collect();
+        recordings.sort_by_key(|r| Reverse(r.estimated_size));
+        recordings.truncate(n);
+        recordings
+    }
+    /// Returns the `n` longest recordings
+    pub fn longest_recordings(&self, n: usize) -> Vec<&RecordingUsage> {
+        let mut recordings: Vec<&RecordingUsage> = self.recordings.iter().collect();
+        recordings.sort_by_key(|r| Reverse(r.info.length));
+        recordings.truncate(n);
+        recordings
+    }
+    /// Returns the usage of each channel, the channel using the most space first
+    pub fn by_channel(&self) -> Vec<ChannelUsage> {
+        let mut channels: HashMap<&str, ChannelUsage> = HashMap::new();
+        for r in self.recordings.iter() {
+            let channel = channels.entry(&r.info.channel).or_insert_with(|| ChannelUsage {
+                channel: r.info.channel.clone(),
+                recordings: 0,
+                length: 0,
+                estimated_size: FolderSize::default(),
+            });
+            channel.recordings += 1;
+            channel.length += r.info.length.max(0) as u64;
+            channel.estimated_size = channel.estimated_size + r.estimated_size;
+        }
+        let mut channels: Vec<ChannelUsage> = channels.into_iter().map(|(_, c)| c).collect();
+        channels.sort_by(|a, b| b.estimated_size.cmp(&a.estimated_size).then(a.channel.cmp(&b.channel)));
+        channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Usage;
+    use session::Session;
+    use transport::FixtureTransport;
+    use types::FolderId;
+    use urls::EVUrl;
+
+    fn usage() -> Usage {
+        let root = Session::with_transport(FixtureTransport::from_dir("testdata").unwrap()).root().unwrap();
+        Usage::of(&root).unwrap()
+    }
+
+    #[test]
+    fn sums_up_the_tree() {
+        let usage = usage();
+        assert!(usage.folders.len() == 3);
+        assert!(usage.total_size().to_string() == "923.18 GB");
+        assert!(usage.folders[0].recordings == 2);
+        assert!(usage.folders[0].total_recordings == 4);
+        assert!(usage.recordings.len() == 4);
+        assert!(usage.largest_folders(1)[0].info.name == "Test folder");
+        assert!(usage.longest_recordings(1)[0].info.length == usage.recordings.iter().map(|r| r.info.length).max().unwrap());
+        assert!(usage.largest_recordings(4).len() == 4);
+    }
+
+    #[test]
+    fn only_recordings_are_added_up() {
+        // Foldername has no recordings of its own, so its size may include the folder under it
+        let transport = FixtureTransport::from_dir("testdata").unwrap();
+        transport.insert(EVUrl::Folder(FolderId::FolderId(1000001)),
+            "{\"ready_data\":[{\"folders\":[{\"id\":\"1000003\",\"name\":\"Subfolder\",\"size\":\"10.00 GB\",\"has_unwatched\":\"false\",\"has_wildcards\":\"false\",\"has_pin\":\"\",\"recordings_count\":\"1\"}],\"recordings\":[]}]}");
+        transport.insert(EVUrl::Folder(FolderId::FolderId(1000003)),
+            "{\"ready_data\":[{\"folders\":[],\"recordings\":[{\"id\":\"100000005\",\"program_id\":\"1000005\",\"folder_id\":\"1000003\",\"name\":\"Programname\",\"channel\":\"MTV3\",\"start_time\":\"ma 17.03.2014 00:05\",\"timestamp\":\"2014-03-17T00:05:00+0200\",\"viewcount\":\"0\",\"length\":\"25\"}]}]}");
+        let root = Session::with_transport(transport).root().unwrap();
+        let usage = Usage::of(&root).unwrap();
+        let folder = usage.folders.iter().find(|f| f.info.name == "Foldername").unwrap();
+        let subfolder = usage.folders.iter().find(|f| f.info.name == "Subfolder").unwrap();
+        assert!(folder.total_size == subfolder.size);
+        assert!(folder.total_recordings == 1);
+        assert!(usage.total_size().to_string() == "875.87 GB");
+    }
+
+    #[test]
+    fn breaks_down_by_channel() {
+        let usage = usage();
+        let channels = usage.by_channel();
+        assert!(channels.iter().map(|c| c.recordings).sum::<usize>() == 4);
+        let length: u64 = channels.iter().map(|c| c.length).sum();
+        assert!(length == usage.total_length());
+    }
+}
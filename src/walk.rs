@@ -0,0 +1,274 @@
+/* vim: set et: */
+
+use pool::FetchPool;
+use traits::Fetch;
+use types::{EVError, Folder, FolderId, FolderInfo, RecordingInfo};
+
+use std::collections::HashMap;
+use std::default::Default;
+
+/// What to do when fetching a folder fails during a walk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorPolicy {
+    /// Report the folder as failed and continue with the rest of the tree
+    Skip,
+    /// Report the folder as failed and end the walk
+    Abort,
+    /// Fetch the folder again up to the given number of times before skipping it
+    Retry(usize),
+}
+
+/// When a folder is visited relative to its contents
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Order {
+    /// Folders are visited before their recordings and subfolders
+    PreOrder,
+    /// Folders are visited after their recordings and subfolders
+    PostOrder,
+}
+
+/// Options for walking a folder tree
+#[derive(Clone, Debug)]
+pub struct WalkOptions {
+    /// Depth of the deepest folders that are visited, the starting folder being at depth 0.
+    /// `None` walks the whole tree.
+    pub max_depth: Option<usize>,
+    pub on_error: ErrorPolicy,
+    pub order: Order,
+    /// Maximum number of folders fetched concurrently.
+    /// With more than one, folders are fetched ahead of the walk on a `FetchPool`.
+    pub max_in_flight: usize,
+}
+impl Default for WalkOptions {
+    fn default() -> WalkOptions {
+        WalkOptions {
+            max_depth: None,
+            on_error: ErrorPolicy::Skip,
+            order: Order::PreOrder,
+            max_in_flight: 1,
+        }
+    }
+}
+
+/// Item of a walk
+#[derive(Debug)]
+pub enum Entry {
+    /// A folder and its depth under the starting folder
+    Folder(FolderInfo, usize),
+    Recording(RecordingInfo),
+    /// Fetching the folder failed, so its contents are missing from the walk
+    Failed(FolderInfo, EVError),
+}
+
+enum Step {
+    Fetch(FolderInfo, usize),
+    Yield(Entry),
+}
+
+/// Recursive iterator over a folder tree
+/// Folders are fetched lazily as the walk descends into them.
+pub struct Walk {
+    options: WalkOptions,
+    stack: Vec<Step>,
+    aborted: bool,
+    pool: Option<FetchPool>,
+    /// Folders fetched by the pool before the walk reached them
+    prefetched: HashMap<FolderId, Result<Folder, EVError>>,
+}
+
+impl Walk {
+    /// Walks the tree under and including `folder`
+    pub fn new(folder: &Folder, options: WalkOptions) -> Walk {
+        let pool = if options.max_in_flight > 1 { Some(FetchPool::new(options.max_in_flight)) } else { None };
+        let mut walk = Walk {
+            options: options,
+            stack: Vec::new(),
+            aborted: false,
+            pool: pool,
+            prefetched: HashMap::new(),
+        };
+        walk.expand(folder, 0);
+        walk
+    }
+    /// Iterates over the recordings of the walk
+    /// Folders that could not be fetched are yielded as errors.
+    pub fn recordings(self) -> WalkRecordings {
+        WalkRecordings { walk: self }
+    }
+    /// Pushes the contents of `folder` so that they are popped in walk order
+    fn expand(&mut self, folder: &Folder, depth: usize) {
+        let mut steps = Vec::new();
+        if self.options.order == Order::PreOrder {
+            steps.push(Step::Yield(Entry::Folder(folder.info().clone(), depth)));
+        }
+        steps.extend(folder.recordings().map(|r| Step::Yield(Entry::Recording(r.clone()))));
+        if self.options.max_depth.map_or(true, |max| depth < max) {
+            for finfo in folder.folders() {
+                if let Some(ref mut pool) = self.pool {
+                    pool.submit(finfo.clone());
+                }
+                steps.push(Step::Fetch(finfo.clone(), depth + 1));
+            }
+        }
+        if self.options.order == Order::PostOrder {
+            steps.push(Step::Yield(Entry::Folder(folder.info().clone(), depth)));
+        }
+        self.stack.extend(steps.into_iter().rev());
+    }
+    /// Waits for the pool to fetch `finfo`
+    fn prefetched(&mut self, finfo: &FolderInfo) -> Option<Result<Folder, EVError>> {
+        loop {
+            if let Some(result) = self.prefetched.remove(&finfo.id()) {
+                return Some(result);
+            }
+            match self.pool.as_mut().and_then(FetchPool::next_loaded) {
+                Some((loaded, result)) => { self.prefetched.insert(loaded.id(), result); },
+                None => return None
+            }
+        }
+    }
+    fn fetch(&mut self, finfo: &FolderInfo) -> Result<Folder, EVError> {
+        let retries = match self.options.on_error {
+            ErrorPolicy::Retry(n) => n,
+            _ => 0
+        };
+        let mut result = match self.prefetched(finfo) {
+            Some(result) => result,
+            None => finfo.fetch()
+        };
+        for _ in 0..retries {
+            if result.is_ok() {
+                break;
+            }
+            result = finfo.fetch();
+        }
+        result
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Entry;
+    fn next(&mut self) -> Option<Entry> {
+        while !self.aborted {
+            match self.stack.pop() {
+                None => return None,
+                Some(Step::Yield(entry)) => return Some(entry),
+                Some(Step::Fetch(finfo, depth)) => {
+                    match self.fetch(&finfo) {
+                        Ok(folder) => self.expand(&folder, depth),
+                        Err(e) => {
+                            if self.options.on_error == ErrorPolicy::Abort {
+                                // Dropping the pool cancels the folders fetched ahead
+                                self.aborted = true;
+                                self.pool = None;
+                            }
+                            return Some(Entry::Failed(finfo, e));
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over the recordings of a `Walk`
+pub struct WalkRecordings {
+    walk: Walk,
+}
+
+impl Iterator for WalkRecordings {
+    type Item = Result<RecordingInfo, EVError>;
+    fn next(&mut self) -> Option<Result<RecordingInfo, EVError>> {
+        loop {
+            match self.walk.next() {
+                None => return None,
+                Some(Entry::Recording(rinfo)) => return Some(Ok(rinfo)),
+                Some(Entry::Failed(_, e)) => return Some(Err(e)),
+                Some(Entry::Folder(..)) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, ErrorPolicy, Order, Walk, WalkOptions};
+    use session::Session;
+    use std::default::Default;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use transport::{FixtureTransport, Request, Response, Transport};
+    use hyper::status::StatusCode;
+    use types::{EVError, Folder, FolderId};
+    use urls::EVUrl;
+
+    fn root(transport: FixtureTransport) -> Folder {
+        Session::with_transport(transport).root().unwrap()
+    }
+
+    fn names(walk: Walk) -> Vec<String> {
+        walk.map(|entry| match entry {
+            Entry::Folder(finfo, depth) => format!("{}:{}", finfo.name, depth),
+            Entry::Recording(rinfo) => rinfo.name,
+            Entry::Failed(finfo, _) => format!("!{}", finfo.name),
+        }).collect()
+    }
+
+    #[test]
+    fn walks_the_whole_tree() {
+        let root = root(FixtureTransport::from_dir("testdata").unwrap());
+        let pre = names(Walk::new(&root, Default::default()));
+        assert!(pre[0] == "Root:0");
+        assert!(pre.len() == 3 + root.recordings().count() + 2);
+        let post = names(Walk::new(&root, WalkOptions { order: Order::PostOrder, ..Default::default() }));
+        assert!(post.last().unwrap() == "Root:0");
+        let shallow = names(Walk::new(&root, WalkOptions { max_depth: Some(0), ..Default::default() }));
+        assert!(shallow.len() == 1 + root.recordings().count());
+        let parallel = names(Walk::new(&root, WalkOptions { max_in_flight: 4, ..Default::default() }));
+        assert!(parallel == pre);
+    }
+
+    /// Returns a root folder whose folder `Foldername` can not be fetched
+    fn failing_root() -> Folder {
+        let transport = FixtureTransport::from_dir("testdata").unwrap();
+        transport.insert_response(EVUrl::Folder(FolderId::FolderId(1000001)), Response {
+            status: StatusCode::InternalServerError,
+            set_cookies: Vec::new(),
+            body: String::new(),
+        });
+        root(transport)
+    }
+
+    /// Fails the first request for folder `Foldername`
+    struct FlakyTransport {
+        fixtures: FixtureTransport,
+        failed: AtomicBool,
+    }
+
+    impl Transport for FlakyTransport {
+        fn send(&self, request: &Request) -> Result<Response, EVError> {
+            if request.url == EVUrl::Folder(FolderId::FolderId(1000001)) && !self.failed.swap(true, Ordering::SeqCst) {
+                return Ok(Response { status: StatusCode::InternalServerError, set_cookies: Vec::new(), body: String::new() });
+            }
+            self.fixtures.send(request)
+        }
+    }
+
+    #[test]
+    fn reports_folders_that_fail() {
+        let skipped: Vec<_> = failing_root().into_iter().collect();
+        assert!(skipped.iter().filter(|r| r.is_err()).count() == 1);
+        assert!(skipped.iter().filter(|r| r.is_ok()).count() == 3);
+
+        let aborted = names(failing_root().walk(WalkOptions { on_error: ErrorPolicy::Abort, ..Default::default() }));
+        assert!(aborted.last().unwrap() == "!Foldername");
+
+        let retry = WalkOptions { on_error: ErrorPolicy::Retry(1), ..Default::default() };
+        assert!(failing_root().walk(retry.clone()).recordings().filter(|r| r.is_err()).count() == 1);
+        let flaky = FlakyTransport { fixtures: FixtureTransport::from_dir("testdata").unwrap(), failed: AtomicBool::new(false) };
+        let retried: Vec<_> = Session::with_transport(flaky).root().unwrap().walk(retry).recordings().collect();
+        assert!(retried.iter().all(|r| r.is_ok()));
+        assert!(retried.iter().any(|r| r.as_ref().map(|r| r.folder_id == Some(1000001)).unwrap_or(false)));
+        assert!(retried.len() == 4);
+    }
+}